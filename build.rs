@@ -17,6 +17,14 @@ fn main() {
                 target_os = "freebsd"
             )
         },
+        http_transport: {
+            any(
+                target_os = "linux",
+                target_os = "macos",
+                target_os = "freebsd",
+                target_os = "windows"
+            )
+        },
         irc_transport: {
             any(
                 target_os = "linux",
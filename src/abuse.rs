@@ -0,0 +1,58 @@
+use crate::proto::PublicKey;
+use dashmap::DashMap;
+use std::{
+    fmt::Display,
+    sync::atomic::{AtomicU64, Ordering},
+};
+
+/// Ways a neighbor can misbehave that we are able to catch it at
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Offense {
+    // Relayed traffic claiming to come from us, or from a neighbor that would have come to us directly
+    Impersonation,
+    // Sent a message as itself that someone else sealed
+    ForgedSource,
+    // Relayed a reply whose tag doesn't check out
+    ForgedReply,
+}
+
+impl Display for Offense {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Offense::Impersonation => write!(f, "passed off traffic as someone it isn't"),
+            Offense::ForgedSource => write!(f, "sent a message someone else sealed"),
+            Offense::ForgedReply => write!(f, "relayed a reply with a forged tag"),
+        }
+    }
+}
+
+/// Offenses of each neighbor, whose traffic is dropped rather than acted on
+#[derive(Debug, Default)]
+pub struct AbuseTracker {
+    offenses: DashMap<PublicKey, u64>,
+    total: AtomicU64,
+}
+
+impl AbuseTracker {
+    /// Count an offense against the neighbor whose session it arrived on
+    pub fn record(&self, neighbor: PublicKey, offense: Offense) {
+        let offenses = {
+            let mut offenses = self.offenses.entry(neighbor).or_default();
+            *offenses += 1;
+            *offenses
+        };
+        self.total.fetch_add(1, Ordering::Relaxed);
+
+        log::warn!(
+            "{} {}, dropped ({} offenses so far)",
+            neighbor,
+            offense,
+            offenses
+        );
+    }
+
+    /// Offenses of every neighbor put together
+    pub fn total(&self) -> u64 {
+        self.total.load(Ordering::Relaxed)
+    }
+}
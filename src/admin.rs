@@ -0,0 +1,165 @@
+use crate::{
+    error::RouteWeaverError,
+    runtime::{reload_config, RuntimeContext},
+};
+use std::{
+    fs::{remove_dir, remove_file, rename, set_permissions, DirBuilder, Permissions},
+    io::ErrorKind,
+    os::unix::fs::{DirBuilderExt, PermissionsExt},
+    path::{Path, PathBuf},
+    time::Duration,
+};
+use tokio::{
+    io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader},
+    net::{UnixListener, UnixStream},
+    signal::unix::{signal, SignalKind},
+    time::timeout,
+};
+use zeroize::Zeroizing;
+
+// Commands are a single short line, anything longer is not one of ours
+const MAX_COMMAND_LENGTH: u64 = 256;
+
+// Connections are handled one at a time, so an idle client would otherwise hold up every other one
+const COMMAND_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Reload the config whenever we get a SIGHUP
+pub async fn reload_on_hangup(context: RuntimeContext, config_location: PathBuf) {
+    let mut hangup = signal(SignalKind::hangup()).unwrap();
+
+    loop {
+        tokio::select! {
+            Some(()) = hangup.recv() => {}
+            _ = context.shutdown.cancelled() => return,
+            else => return,
+        }
+
+        if let Err(e) = reload_config(&context, &config_location).await {
+            log::error!("Keeping the running config: {}", e);
+        }
+    }
+}
+
+/// Answer admin commands on a Unix socket until we shut down
+pub async fn serve_admin_socket(context: RuntimeContext, path: PathBuf, config_location: PathBuf) {
+    // Left behind by a previous run that didn't get to clean up
+    let _ = remove_file(&path);
+
+    let listener = match bind_private(&path) {
+        Ok(listener) => listener,
+        Err(e) => {
+            log::error!("Failed to open admin socket {}: {}", path.display(), e);
+            return;
+        }
+    };
+
+    loop {
+        let stream = tokio::select! {
+            accepted = listener.accept() => match accepted {
+                Ok((stream, _)) => stream,
+                Err(e) => {
+                    log::error!("Stopped accepting admin connections: {}", e);
+                    break;
+                }
+            },
+            _ = context.shutdown.cancelled() => break,
+        };
+
+        // One at a time, so reloads never overlap
+        if let Err(e) = handle_admin_connection(&context, stream, &config_location).await {
+            log::warn!("Admin connection failed: {}", e);
+        }
+    }
+
+    let _ = remove_file(&path);
+}
+
+/// Bind a socket only our own user may connect to, without a window where anyone else could
+fn bind_private(path: &Path) -> Result<UnixListener, RouteWeaverError> {
+    let name = path
+        .file_name()
+        .ok_or_else(|| RouteWeaverError::InvalidConfig("admin_socket names no file".to_string()))?;
+    let mut staging_name = std::ffi::OsString::from(".");
+    staging_name.push(name);
+    staging_name.push(format!(".{}", std::process::id()));
+    let staging = path.with_file_name(staging_name);
+
+    // Nobody else can reach into this directory while the socket is still open to all
+    DirBuilder::new().mode(0o700).create(&staging)?;
+    let staged = staging.join("socket");
+
+    let bound = UnixListener::bind(&staged).and_then(|listener| {
+        // Commands reconfigure the whole node, so only our own user may send them
+        set_permissions(&staged, Permissions::from_mode(0o600))?;
+        rename(&staged, path)?;
+        Ok(listener)
+    });
+
+    let _ = remove_file(&staged);
+    let _ = remove_dir(&staging);
+
+    Ok(bound?)
+}
+
+async fn handle_admin_connection(
+    context: &RuntimeContext,
+    stream: UnixStream,
+    config_location: &Path,
+) -> Result<(), RouteWeaverError> {
+    let (reader, mut writer) = stream.into_split();
+
+    let mut command = String::new();
+    timeout(
+        COMMAND_TIMEOUT,
+        BufReader::new(reader.take(MAX_COMMAND_LENGTH)).read_line(&mut command),
+    )
+    .await
+    .map_err(|_| std::io::Error::from(ErrorKind::TimedOut))??;
+
+    let response = match command.trim() {
+        "reload" => match reload_config(context, config_location).await {
+            Ok(()) => "ok".to_string(),
+            Err(e) => {
+                log::error!("Keeping the running config: {}", e);
+                format!("error: {}", e)
+            }
+        },
+        other => format!("error: unknown command {:?}", other),
+    };
+
+    writer.write_all(response.as_bytes()).await?;
+    writer.write_all(b"\n").await?;
+    writer.shutdown().await?;
+
+    Ok(())
+}
+
+/// Admin socket named by a config file, found without loading the identity in it
+pub fn admin_socket_location(config_location: &Path) -> Result<PathBuf, RouteWeaverError> {
+    let contents = Zeroizing::new(std::fs::read_to_string(config_location)?);
+    let config: toml::Table =
+        toml::from_str(&contents).map_err(|e| RouteWeaverError::InvalidConfig(e.to_string()))?;
+
+    config
+        .get("admin_socket")
+        .and_then(|path| path.as_str())
+        .map(PathBuf::from)
+        .ok_or_else(|| RouteWeaverError::InvalidConfig("admin_socket is not set".to_string()))
+}
+
+/// Send a command to the running daemon and hand back its response
+pub async fn send_admin_command(path: &Path, command: &str) -> Result<String, RouteWeaverError> {
+    let mut stream = UnixStream::connect(path).await?;
+    stream
+        .write_all(format!("{}\n", command).as_bytes())
+        .await?;
+
+    let mut response = String::new();
+    stream.read_to_string(&mut response).await?;
+    let response = response.trim_end();
+
+    match response.strip_prefix("error: ") {
+        Some(e) => Err(RouteWeaverError::Admin(e.to_string())),
+        None => Ok(response.to_string()),
+    }
+}
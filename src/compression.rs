@@ -0,0 +1,265 @@
+use crate::{
+    config::TransportConfig,
+    error::RouteWeaverError,
+    proto::{DictionaryId, MessageCompressionMode, Protocol},
+};
+use blake2::{Blake2s256, Digest};
+use entropy::shannon_entropy;
+use indexmap::IndexMap;
+use std::{io::Read, path::PathBuf};
+
+const DEFAULT_ZLIB_LEVEL: u8 = 6;
+const DEFAULT_ZSTD_LEVEL: i32 = 3;
+const DEFAULT_MIN_LENGTH: usize = 128;
+// Bits per byte, so 8 is indistinguishable from random noise
+const DEFAULT_MAX_ENTROPY: f32 = 7.5;
+
+/// How a transport wants the messages it carries compressed
+#[derive(Debug, Clone, PartialEq)]
+pub struct CompressionPolicy {
+    // Every mode worth trying, the smallest output wins
+    pub modes: Vec<MessageCompressionMode>,
+    pub zlib_level: u8,
+    pub zstd_level: i32,
+    // Shorter messages are sent as is
+    pub min_length: usize,
+    // Data denser than this is assumed to be compressed or encrypted already
+    pub max_entropy: f32,
+}
+
+impl Default for CompressionPolicy {
+    fn default() -> Self {
+        Self {
+            modes: vec![
+                MessageCompressionMode::Lz4,
+                MessageCompressionMode::Zlib,
+                MessageCompressionMode::Zstd { dictionary: None },
+            ],
+            zlib_level: DEFAULT_ZLIB_LEVEL,
+            zstd_level: DEFAULT_ZSTD_LEVEL,
+            min_length: DEFAULT_MIN_LENGTH,
+            max_entropy: DEFAULT_MAX_ENTROPY,
+        }
+    }
+}
+
+impl CompressionPolicy {
+    pub fn default_for(protocol: Protocol) -> Self {
+        match protocol {
+            // Copying memory around is cheaper than compressing it
+            Protocol::Unix => Self {
+                modes: Vec::new(),
+                ..Default::default()
+            },
+            // Every byte counts on these, so spend the time
+            Protocol::Irc | Protocol::Bluetooth => Self {
+                modes: vec![
+                    MessageCompressionMode::Zlib,
+                    MessageCompressionMode::Zstd { dictionary: None },
+                ],
+                zlib_level: 9,
+                zstd_level: 19,
+                min_length: 64,
+                ..Default::default()
+            },
+            Protocol::Tcp | Protocol::Http => Self::default(),
+        }
+    }
+
+    /// Read the compression keys of a transport's config on top of its defaults
+    pub fn from_transport_config(
+        protocol: Protocol,
+        config: Option<&TransportConfig>,
+    ) -> Result<Self, RouteWeaverError> {
+        let mut policy = Self::default_for(protocol);
+        let invalid = |key: &str| {
+            RouteWeaverError::TransportConfig(format!("{} has an invalid {}", protocol, key))
+        };
+
+        let Some(config) = config else {
+            return Ok(policy);
+        };
+
+        if let Some(value) = config.get("compression") {
+            policy.modes = value
+                .as_array()
+                .ok_or_else(|| invalid("compression"))?
+                .iter()
+                .map(|mode| match mode.as_str() {
+                    Some("lz4") => Ok(MessageCompressionMode::Lz4),
+                    Some("zlib") => Ok(MessageCompressionMode::Zlib),
+                    // Whether a dictionary gets used is up to the peer on the other end
+                    Some("zstd") => Ok(MessageCompressionMode::Zstd { dictionary: None }),
+                    _ => Err(invalid("compression")),
+                })
+                .collect::<Result<_, _>>()?;
+        }
+
+        if let Some(value) = config.get("compression_level") {
+            policy.zlib_level = value
+                .as_integer()
+                .and_then(|level| u8::try_from(level).ok())
+                .filter(|level| *level <= 10)
+                .ok_or_else(|| invalid("compression_level"))?;
+        }
+
+        if let Some(value) = config.get("compression_zstd_level") {
+            policy.zstd_level = value
+                .as_integer()
+                .and_then(|level| i32::try_from(level).ok())
+                .filter(|level| zstd::compression_level_range().contains(level))
+                .ok_or_else(|| invalid("compression_zstd_level"))?;
+        }
+
+        if let Some(value) = config.get("compression_min_length") {
+            policy.min_length = value
+                .as_integer()
+                .and_then(|length| usize::try_from(length).ok())
+                .ok_or_else(|| invalid("compression_min_length"))?;
+        }
+
+        if let Some(value) = config.get("compression_max_entropy") {
+            policy.max_entropy = value
+                .as_float()
+                .or_else(|| value.as_integer().map(|entropy| entropy as f64))
+                .filter(|entropy| (0.0..=8.0).contains(entropy))
+                .ok_or_else(|| invalid("compression_max_entropy"))?
+                as f32;
+        }
+
+        Ok(policy)
+    }
+
+    /// Compress with whichever allowed mode does best, or not at all if none of them pay off
+    ///
+    /// Zstd uses `dictionary` when one is given, which the receiver has to hold as well
+    pub fn compress(
+        &self,
+        data: Vec<u8>,
+        dictionary: Option<(DictionaryId, &[u8])>,
+    ) -> (Option<MessageCompressionMode>, Vec<u8>) {
+        if self.modes.is_empty()
+            || data.len() < self.min_length
+            || shannon_entropy(&data) > self.max_entropy
+        {
+            return (None, data);
+        }
+
+        let best = self
+            .modes
+            .iter()
+            .filter_map(|mode| self.compress_with(*mode, &data, dictionary))
+            .filter(|(_, compressed)| compressed.len() < data.len())
+            .min_by_key(|(_, compressed)| compressed.len());
+
+        match best {
+            Some((mode, compressed)) => (Some(mode), compressed),
+            None => (None, data),
+        }
+    }
+
+    fn compress_with(
+        &self,
+        mode: MessageCompressionMode,
+        data: &[u8],
+        dictionary: Option<(DictionaryId, &[u8])>,
+    ) -> Option<(MessageCompressionMode, Vec<u8>)> {
+        match mode {
+            MessageCompressionMode::Lz4 => Some((mode, lz4_flex::compress_prepend_size(data))),
+            MessageCompressionMode::Zlib => Some((
+                mode,
+                miniz_oxide::deflate::compress_to_vec_zlib(data, self.zlib_level),
+            )),
+            MessageCompressionMode::Zstd { .. } => {
+                let (dictionary_id, dictionary) = dictionary.unzip();
+                let compressed = zstd::bulk::Compressor::with_dictionary(
+                    self.zstd_level,
+                    dictionary.unwrap_or_default(),
+                )
+                .and_then(|mut compressor| compressor.compress(data));
+
+                match compressed {
+                    Ok(compressed) => Some((
+                        MessageCompressionMode::Zstd {
+                            dictionary: dictionary_id,
+                        },
+                        compressed,
+                    )),
+                    Err(e) => {
+                        log::warn!("Zstd compression failed: {}", e);
+                        None
+                    }
+                }
+            }
+        }
+    }
+}
+
+/// Shared zstd dictionaries, in the order we prefer to use them
+#[derive(Debug, Default)]
+pub struct ZstdDictionaries {
+    dictionaries: IndexMap<DictionaryId, Vec<u8>>,
+}
+
+impl ZstdDictionaries {
+    pub fn load(paths: &[PathBuf]) -> Result<Self, RouteWeaverError> {
+        let mut dictionaries = Self::default();
+
+        for path in paths {
+            let id = dictionaries.insert(std::fs::read(path)?);
+            log::info!("Loaded zstd dictionary {} from {}", id, path.display());
+        }
+
+        Ok(dictionaries)
+    }
+
+    pub fn insert(&mut self, dictionary: Vec<u8>) -> DictionaryId {
+        let id = DictionaryId(Blake2s256::digest(&dictionary).into());
+        self.dictionaries.insert(id, dictionary);
+
+        id
+    }
+
+    pub fn ids(&self) -> Vec<DictionaryId> {
+        self.dictionaries.keys().copied().collect()
+    }
+
+    pub fn get(&self, id: &DictionaryId) -> Option<(DictionaryId, &[u8])> {
+        self.dictionaries
+            .get(id)
+            .map(|dictionary| (*id, dictionary.as_slice()))
+    }
+
+    /// Our most preferred dictionary that the remote also holds
+    pub fn first_shared(&self, remote: &[DictionaryId]) -> Option<DictionaryId> {
+        self.dictionaries
+            .keys()
+            .find(|id| remote.contains(id))
+            .copied()
+    }
+
+    /// Decompress a zstd frame without ever producing more than `max_size` bytes
+    pub fn decompress(
+        &self,
+        dictionary: Option<DictionaryId>,
+        data: &[u8],
+        max_size: usize,
+    ) -> Result<Vec<u8>, RouteWeaverError> {
+        let dictionary = match dictionary {
+            Some(id) => self.get(&id).ok_or(RouteWeaverError::Decompression)?.1,
+            None => &[],
+        };
+
+        let mut decompressed = Vec::new();
+        zstd::stream::read::Decoder::with_dictionary(data, dictionary)?
+            .take((max_size as u64).saturating_add(1))
+            .read_to_end(&mut decompressed)
+            .map_err(|_| RouteWeaverError::Decompression)?;
+
+        if decompressed.len() > max_size {
+            return Err(RouteWeaverError::MessageTooLarge);
+        }
+
+        Ok(decompressed)
+    }
+}
@@ -1,28 +1,272 @@
-use crate::proto::{Peer, PrivateKey, Protocol, PublicKey};
-use serde::{Deserialize, Serialize};
+use crate::{
+    compression::CompressionPolicy,
+    error::RouteWeaverError,
+    identity::{parse_private_key, read_key_file},
+    peer::derive_public_key,
+    proto::{Address, Peer, PrivateKey, Protocol, PublicKey, MAX_DECOMPRESSED_MESSAGE_SIZE},
+    reassembly::ReassemblyLimits,
+    transport,
+};
+use serde::Deserialize;
 use serde_with::serde_as;
 use serde_with::DisplayFromStr;
-use std::collections::{HashMap, HashSet};
+use std::{
+    collections::{HashMap, HashSet},
+    fmt::Display,
+    path::{Path, PathBuf},
+    str::FromStr,
+    sync::Arc,
+};
 use toml::Value;
+use zeroize::Zeroizing;
 
 pub type TransportConfig = HashMap<String, Value>;
 
+// Where the private key comes from when the config names no source for it
+pub const PRIVATE_KEY_VARIABLE: &str = "ROUTEWEAVER_PRIVATE_KEY";
+
+fn read_key_variable(variable: &str) -> Result<PrivateKey, RouteWeaverError> {
+    let contents = Zeroizing::new(std::env::var(variable).map_err(|_| {
+        RouteWeaverError::InvalidConfig(format!("no private key configured or in ${}", variable))
+    })?);
+
+    parse_private_key(&contents)
+}
+
+/// Config as written on disk, before the identity in it has been checked
 #[serde_as]
-#[derive(Serialize, Deserialize, Debug)]
+#[derive(Deserialize)]
 #[serde(deny_unknown_fields)]
-pub struct Config {
-    #[serde_as(as = "DisplayFromStr")]
-    pub public_key: PublicKey,
-    #[serde_as(as = "DisplayFromStr")]
-    pub private_key: PrivateKey,
+struct ConfigFile {
+    // Derived from the private key when left out
     #[serde(default)]
-    pub enabled_transports: HashSet<Protocol>,
+    #[serde_as(as = "Option<DisplayFromStr>")]
+    public_key: Option<PublicKey>,
+    // Exactly one source for the private key, falling back to PRIVATE_KEY_VARIABLE
     #[serde(default)]
-    pub transport_configs: HashMap<Protocol, TransportConfig>,
+    private_key: Option<Zeroizing<String>>,
+    #[serde(default)]
+    private_key_file: Option<PathBuf>,
+    #[serde(default)]
+    private_key_env: Option<String>,
+    #[serde(default)]
+    enabled_transports: HashSet<Protocol>,
+    #[serde(default)]
+    transport_configs: HashMap<Protocol, TransportConfig>,
     #[serde(default)]
     #[serde_as(as = "HashSet<DisplayFromStr>")]
-    pub seeders: HashSet<Peer>,
+    seeders: HashSet<Peer>,
     #[serde(default)]
     #[serde_as(as = "HashSet<DisplayFromStr>")]
-    pub deny_list: HashSet<Peer>,
+    deny_list: HashSet<DenyListEntry>,
+    #[serde(default)]
+    reassembly: ReassemblyLimits,
+    // Anything inflating beyond this is treated as a decompression bomb
+    #[serde(default = "default_max_decompressed_message_size")]
+    max_decompressed_message_size: usize,
+    // Shared zstd dictionaries, most preferred first
+    #[serde(default)]
+    zstd_dictionaries: Vec<PathBuf>,
+    // Where the daemon listens for admin commands such as reload
+    #[serde(default)]
+    admin_socket: Option<PathBuf>,
+}
+
+#[derive(Deserialize, Debug)]
+#[serde(try_from = "ConfigFile")]
+pub struct Config {
+    pub public_key: PublicKey,
+    // As written in the file, checked against the derived one by validate
+    claimed_public_key: Option<PublicKey>,
+    // Shared with the listeners, which answer for us without holding on to the whole config
+    pub private_key: Arc<PrivateKey>,
+    pub enabled_transports: HashSet<Protocol>,
+    pub transport_configs: HashMap<Protocol, TransportConfig>,
+    pub seeders: HashSet<Peer>,
+    pub deny_list: HashSet<DenyListEntry>,
+    pub reassembly: ReassemblyLimits,
+    pub max_decompressed_message_size: usize,
+    pub zstd_dictionaries: Vec<PathBuf>,
+    pub admin_socket: Option<PathBuf>,
+}
+
+impl TryFrom<ConfigFile> for Config {
+    type Error = RouteWeaverError;
+
+    fn try_from(file: ConfigFile) -> Result<Self, Self::Error> {
+        let private_key = match (
+            file.private_key,
+            file.private_key_file,
+            file.private_key_env,
+        ) {
+            (Some(private_key), None, None) => parse_private_key(&private_key)?,
+            (None, Some(path), None) => read_key_file(&path)?,
+            (None, None, Some(variable)) => read_key_variable(&variable)?,
+            (None, None, None) => read_key_variable(PRIVATE_KEY_VARIABLE)?,
+            _ => {
+                return Err(RouteWeaverError::InvalidConfig(
+                    "only one of private_key, private_key_file and private_key_env may be set"
+                        .to_string(),
+                ))
+            }
+        };
+        Ok(Self {
+            public_key: derive_public_key(&private_key),
+            claimed_public_key: file.public_key,
+            private_key: Arc::new(private_key),
+            enabled_transports: file.enabled_transports,
+            transport_configs: file.transport_configs,
+            seeders: file.seeders,
+            deny_list: file.deny_list,
+            reassembly: file.reassembly,
+            max_decompressed_message_size: file.max_decompressed_message_size,
+            zstd_dictionaries: file.zstd_dictionaries,
+            admin_socket: file.admin_socket,
+        })
+    }
+}
+
+pub fn default_max_decompressed_message_size() -> usize {
+    16 * 1024 * 1024
+}
+
+impl Config {
+    /// Read, parse and validate the config file at `path`
+    pub fn load(path: &Path) -> Result<Self, RouteWeaverError> {
+        // The config may well hold our private key
+        let contents = Zeroizing::new(std::fs::read_to_string(path)?);
+        let config: Self = toml::from_str(&contents)
+            .map_err(|e| RouteWeaverError::InvalidConfig(e.to_string()))?;
+
+        config.validate()?;
+
+        Ok(config)
+    }
+
+    /// Catch settings that would only fail once the daemon is already running
+    pub fn validate(&self) -> Result<(), RouteWeaverError> {
+        // A typo here would otherwise only show up as every handshake failing
+        if self
+            .claimed_public_key
+            .is_some_and(|claimed| claimed != self.public_key)
+        {
+            return Err(RouteWeaverError::KeyMismatch);
+        }
+
+        if self.enabled_transports.is_empty() {
+            return Err(RouteWeaverError::InvalidConfig(
+                "no transports are enabled".to_string(),
+            ));
+        }
+
+        for protocol in &self.enabled_transports {
+            if !transport::is_available(*protocol) {
+                return Err(RouteWeaverError::InvalidConfig(format!(
+                    "the {} transport is not available in this build",
+                    protocol
+                )));
+            }
+
+            CompressionPolicy::from_transport_config(
+                *protocol,
+                self.transport_configs.get(protocol),
+            )?;
+        }
+
+        for protocol in self.transport_configs.keys() {
+            if !self.enabled_transports.contains(protocol) {
+                log::warn!(
+                    "Ignoring config for the {} transport as it is not enabled",
+                    protocol
+                );
+            }
+        }
+
+        if self.max_decompressed_message_size > MAX_DECOMPRESSED_MESSAGE_SIZE {
+            return Err(RouteWeaverError::InvalidConfig(format!(
+                "max_decompressed_message_size may be at most {} bytes",
+                MAX_DECOMPRESSED_MESSAGE_SIZE
+            )));
+        }
+
+        // Only read in full once the daemon starts, which is too late to find out one is missing
+        for path in &self.zstd_dictionaries {
+            let readable = std::fs::File::open(path)
+                .and_then(|file| file.metadata())
+                .is_ok_and(|metadata| metadata.is_file());
+
+            if !readable {
+                return Err(RouteWeaverError::InvalidConfig(format!(
+                    "zstd dictionary {} is not a readable file",
+                    path.display()
+                )));
+            }
+        }
+
+        for seeder in &self.seeders {
+            if !self.enabled_transports.contains(&seeder.protocol) {
+                return Err(RouteWeaverError::InvalidConfig(format!(
+                    "seeder {} needs the {} transport enabled",
+                    seeder, seeder.protocol
+                )));
+            }
+
+            if self.is_denied_peer(seeder) {
+                return Err(RouteWeaverError::InvalidConfig(format!(
+                    "seeder {} is on the deny list",
+                    seeder
+                )));
+            }
+        }
+
+        Ok(())
+    }
+
+    pub fn is_denied_peer(&self, peer: &Peer) -> bool {
+        if self.deny_list.contains(&DenyListEntry::Peer(peer.clone())) {
+            return true;
+        }
+
+        // Denying an IP covers every port on it
+        match &peer.address {
+            Address::Socket(socket_addr) => self.deny_list.contains(&DenyListEntry::Peer(Peer {
+                protocol: peer.protocol,
+                address: Address::Ip(socket_addr.ip()),
+            })),
+            _ => false,
+        }
+    }
+
+    pub fn is_denied_key(&self, key: &PublicKey) -> bool {
+        self.deny_list.contains(&DenyListEntry::PublicKey(*key))
+    }
+}
+
+/// Either a transport address or a node identity that survives address changes
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub enum DenyListEntry {
+    Peer(Peer),
+    PublicKey(PublicKey),
+}
+
+impl Display for DenyListEntry {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            DenyListEntry::Peer(peer) => peer.fmt(f),
+            DenyListEntry::PublicKey(key) => key.fmt(f),
+        }
+    }
+}
+
+impl FromStr for DenyListEntry {
+    type Err = RouteWeaverError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        // Addresses always contain a protocol separator while keys never do
+        if s.contains('@') {
+            Ok(DenyListEntry::Peer(s.parse()?))
+        } else {
+            Ok(DenyListEntry::PublicKey(s.parse()?))
+        }
+    }
 }
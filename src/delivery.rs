@@ -0,0 +1,157 @@
+use crate::{
+    error::RouteWeaverError,
+    proto::{MessageId, MessageSegment, Packet, PublicKey},
+};
+use std::{
+    collections::HashMap,
+    iter::once,
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+use tokio::sync::oneshot;
+
+// How long a reliable message may go unanswered before its end is sent again
+pub const RETRANSMIT_TIMEOUT: Duration = Duration::from_secs(5);
+// Rounds of sending after which a message is given up on
+pub const MAX_DELIVERY_ATTEMPTS: u32 = 8;
+
+/// Told whether a reliable message arrived, dropped unanswered if it could not be sent at all
+pub type DeliveryConfirmation = oneshot::Sender<Result<(), RouteWeaverError>>;
+
+// Destination and identifier of a message we sent
+pub type DeliveryKey = (PublicKey, MessageId);
+
+#[derive(Debug)]
+struct OutgoingMessage {
+    // Indexed by segment index
+    segments: Vec<Packet>,
+    end: Packet,
+    attempts: u32,
+    last_sent: Instant,
+    confirmation: DeliveryConfirmation,
+}
+
+impl OutgoingMessage {
+    fn fail(self, destination: PublicKey) {
+        log::warn!(
+            "Giving up on a message to {} after {} attempts",
+            destination,
+            self.attempts
+        );
+
+        let _ = self
+            .confirmation
+            .send(Err(RouteWeaverError::Unacknowledged {
+                destination,
+                attempts: self.attempts,
+            }));
+    }
+}
+
+/// Reliable messages we sent, held on to until their destination acknowledges them
+#[derive(Debug, Default)]
+pub struct DeliveryTracker {
+    messages: Mutex<HashMap<DeliveryKey, OutgoingMessage>>,
+}
+
+impl DeliveryTracker {
+    /// Keep the packets of a freshly segmented message around in case they need resending
+    pub fn track(&self, packets: &[Packet], confirmation: DeliveryConfirmation) {
+        let Some((end, segments)) = packets.split_last() else {
+            return;
+        };
+
+        let MessageSegment::EndMessage { id, .. } = &end.message else {
+            return;
+        };
+
+        self.messages.lock().unwrap().insert(
+            (end.destination, *id),
+            OutgoingMessage {
+                segments: segments.to_vec(),
+                end: end.clone(),
+                attempts: 1,
+                last_sent: Instant::now(),
+                confirmation,
+            },
+        );
+    }
+
+    /// The destination has the whole message, so whoever sent it can stop waiting
+    pub fn acknowledge(&self, key: &DeliveryKey) {
+        let Some(message) = self.messages.lock().unwrap().remove(key) else {
+            return;
+        };
+
+        log::debug!("{} acknowledged message {}", key.0, key.1);
+        let _ = message.confirmation.send(Ok(()));
+    }
+
+    /// Segments the destination asked for again, followed by the end so it takes stock once more
+    pub fn retransmit(&self, key: &DeliveryKey, indexes: &[u32]) -> Vec<Packet> {
+        let mut messages = self.messages.lock().unwrap();
+        let Some(message) = messages.get_mut(key) else {
+            return Vec::new();
+        };
+
+        if message.attempts >= MAX_DELIVERY_ATTEMPTS {
+            if let Some(message) = messages.remove(key) {
+                message.fail(key.0);
+            }
+
+            return Vec::new();
+        }
+
+        message.attempts += 1;
+        message.last_sent = Instant::now();
+
+        log::debug!(
+            "Resending {} segments of message {} to {}",
+            indexes.len(),
+            key.1,
+            key.0
+        );
+
+        indexes
+            .iter()
+            .filter_map(|index| message.segments.get(*index as usize))
+            .chain(once(&message.end))
+            .cloned()
+            .collect()
+    }
+
+    /// Ends of the messages that went unanswered for too long, giving up on the ones out of attempts
+    pub fn due(&self) -> Vec<Packet> {
+        let mut messages = self.messages.lock().unwrap();
+
+        let exhausted = messages
+            .iter()
+            .filter(|(_, message)| {
+                message.attempts >= MAX_DELIVERY_ATTEMPTS
+                    && message.last_sent.elapsed() > RETRANSMIT_TIMEOUT
+            })
+            .map(|(key, _)| *key)
+            .collect::<Vec<_>>();
+
+        for key in exhausted {
+            if let Some(message) = messages.remove(&key) {
+                message.fail(key.0);
+            }
+        }
+
+        messages
+            .values_mut()
+            .filter(|message| message.last_sent.elapsed() > RETRANSMIT_TIMEOUT)
+            .map(|message| {
+                message.attempts += 1;
+                message.last_sent = Instant::now();
+
+                message.end.clone()
+            })
+            .collect()
+    }
+
+    pub fn pending_messages(&self) -> usize {
+        self.messages.lock().unwrap().len()
+    }
+}
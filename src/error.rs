@@ -4,16 +4,53 @@ use thiserror::Error;
 pub enum RouteWeaverError {
     #[error("io error: {0}")]
     Standard(#[from] std::io::Error),
-    #[error("compute module error")]
-    IncorrectComputeModuleBehavior,
     #[error("packet encoding error")]
     PacketEncoding,
     #[error("packet decoding error {0}")]
     PacketDecoding(#[from] bincode::error::DecodeError),
     #[error("transport connection error")]
     TransportConnection,
+    #[error("transport config error: {0}")]
+    TransportConfig(String),
     #[error("peer address error")]
     PeerAddress,
     #[error("key parsing error")]
     KeyParsingError,
+    #[error("public key does not belong to the private key")]
+    KeyMismatch,
+    #[error("key file {0} must only be accessible by its owner")]
+    InsecureKeyFile(std::path::PathBuf),
+    #[error("invalid config: {0}")]
+    InvalidConfig(String),
+    #[error("noise error: {0}")]
+    Noise(#[from] snow::Error),
+    #[error("handshake error")]
+    Handshake,
+    #[error("unexpected message segment")]
+    UnexpectedSegment,
+    #[error("sealed message is malformed")]
+    MalformedSeal,
+    #[error("message was not sealed by {0}, the source it claims")]
+    ForgedSource(crate::proto::PublicKey),
+    #[error("reply does not carry a valid tag from {0}")]
+    ForgedReply(crate::proto::PublicKey),
+    #[error("message too large")]
+    MessageTooLarge,
+    #[error("message of {size} bytes is larger than the {limit} bytes its path can carry")]
+    MessageTooLargeForPath { size: usize, limit: usize },
+    #[error("no route to destination")]
+    NoRoute,
+    #[error("message decompression error")]
+    Decompression,
+    #[error("peer is on the deny list")]
+    Denied,
+    #[error("peer speaks protocol edition {0}")]
+    IncompatibleEdition(u8),
+    #[error("{destination} did not acknowledge the message after {attempts} attempts")]
+    Unacknowledged {
+        destination: crate::proto::PublicKey,
+        attempts: u32,
+    },
+    #[error("admin command failed: {0}")]
+    Admin(String),
 }
@@ -0,0 +1,150 @@
+use crate::{
+    config::{default_max_decompressed_message_size, PRIVATE_KEY_VARIABLE},
+    error::RouteWeaverError,
+    peer::{create_keypair, derive_public_key},
+    proto::{PrivateKey, PublicKey},
+    reassembly::ReassemblyLimits,
+};
+use std::{
+    fs::{File, OpenOptions},
+    io::Write,
+    path::Path,
+};
+use zeroize::Zeroizing;
+
+/// Write a file only its owner can read, refusing to clobber an existing one unless forced to
+pub fn write_secret_file(path: &Path, contents: &str, force: bool) -> Result<(), RouteWeaverError> {
+    let mut options = OpenOptions::new();
+    options.write(true);
+
+    if force {
+        options.create(true).truncate(true);
+    } else {
+        options.create_new(true);
+    }
+
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::{OpenOptionsExt, PermissionsExt};
+
+        options.mode(0o600);
+
+        // The mode only applies to new files, so tighten up one we are overwriting too
+        if force && path.exists() {
+            std::fs::set_permissions(path, std::fs::Permissions::from_mode(0o600))?;
+        }
+    }
+
+    let mut file = options.open(path)?;
+    file.write_all(contents.as_bytes())?;
+    file.sync_all()?;
+
+    Ok(())
+}
+
+pub fn parse_private_key(contents: &str) -> Result<PrivateKey, RouteWeaverError> {
+    contents.trim().parse()
+}
+
+/// Read a hex private key, refusing files anyone but their owner could get at
+pub fn read_key_file(path: &Path) -> Result<PrivateKey, RouteWeaverError> {
+    let file = File::open(path)?;
+
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::PermissionsExt;
+
+        // Checked on the open file so it can't be swapped out from under us
+        if file.metadata()?.permissions().mode() & 0o077 != 0 {
+            return Err(RouteWeaverError::InsecureKeyFile(path.to_path_buf()));
+        }
+    }
+
+    let contents = Zeroizing::new(std::io::read_to_string(file)?);
+    parse_private_key(&contents)
+}
+
+/// Generate a fresh identity and store its private key as hex in `path`
+pub fn keygen(path: &Path, force: bool) -> Result<PublicKey, RouteWeaverError> {
+    let (public_key, private_key) = create_keypair();
+
+    write_secret_file(path, &Zeroizing::new(format!("{}\n", private_key)), force)?;
+
+    Ok(public_key)
+}
+
+/// Public key belonging to the hex private key in `path`, or on stdin if there is no path
+pub fn pubkey(path: Option<&Path>) -> Result<PublicKey, RouteWeaverError> {
+    let private_key = match path {
+        Some(path) => read_key_file(path)?,
+        None => parse_private_key(&Zeroizing::new(std::io::read_to_string(std::io::stdin())?))?,
+    };
+
+    Ok(derive_public_key(&private_key))
+}
+
+/// Write a complete config with a fresh identity to `path`
+pub fn init(path: &Path, force: bool) -> Result<PublicKey, RouteWeaverError> {
+    let (public_key, private_key) = create_keypair();
+
+    if let Some(parent) = path
+        .parent()
+        .filter(|parent| !parent.as_os_str().is_empty())
+    {
+        std::fs::create_dir_all(parent)?;
+    }
+
+    write_secret_file(
+        path,
+        &Zeroizing::new(scaffold_config(&public_key, &private_key)),
+        force,
+    )?;
+
+    Ok(public_key)
+}
+
+/// Config with every option spelled out at its default
+pub fn scaffold_config(public_key: &PublicKey, private_key: &PrivateKey) -> String {
+    let reassembly = ReassemblyLimits::default();
+
+    format!(
+        r#"# Identity of this node, the private key must never leave it
+# It can also live in private_key_file, the variable named by private_key_env or ${private_key_variable}
+public_key = "{public_key}"
+private_key = "{private_key}"
+
+enabled_transports = ["tcp"]
+
+# Peers to dial on startup, such as "tcp@192.0.2.1:3434" or "unix@/run/routeweaver.sock"
+seeders = []
+
+# Peers or public keys we refuse to talk to
+deny_list = []
+
+# Shared zstd dictionaries, most preferred first
+zstd_dictionaries = []
+
+# Anything inflating beyond this many bytes is dropped
+max_decompressed_message_size = {max_decompressed_message_size}
+
+# Socket `routeweaver reload` talks to, SIGHUP reloads the config as well
+# admin_socket = "/run/routeweaver/admin.sock"
+
+[transport_configs.tcp]
+bind = "::"
+port = 3434
+
+[reassembly]
+message_timeout = {message_timeout}
+max_bytes_per_source = {max_bytes_per_source}
+max_total_bytes = {max_total_bytes}
+max_messages = {max_messages}
+"#,
+        private_key_variable = PRIVATE_KEY_VARIABLE,
+        max_decompressed_message_size = default_max_decompressed_message_size(),
+        message_timeout = reassembly.message_timeout.as_secs(),
+        max_bytes_per_source = reassembly.max_bytes_per_source,
+        max_total_bytes = reassembly.max_total_bytes,
+        max_messages = reassembly.max_messages,
+    )
+}
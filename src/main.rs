@@ -1,21 +1,38 @@
-use clap::Parser;
+use abuse::AbuseTracker;
+use clap::{Parser, Subcommand};
+use compression::ZstdDictionaries;
 use config::Config;
 use dashmap::DashMap;
+use error::RouteWeaverError;
 
 use deadqueue::unlimited::Queue;
-use proto::Protocol;
-use runtime::accept_connections_from_peers;
-use scc::HashCache;
-use std::{path::PathBuf, sync::Arc, time::Duration};
-use tokio::time::sleep;
-use transport::Transport;
+use delivery::DeliveryTracker;
+use reassembly::MessageTracker;
+use routing::RoutingTable;
+use runtime::{
+    advertise_routes, encode_clear_text_message, expire_pre_assembled_messages, manage_transports,
+    relay_segments, retransmit_unacknowledged, route_encoded_message, shutdown, RuntimeContext,
+};
+use std::{path::PathBuf, sync::Arc};
+use tokio::sync::{mpsc::channel, watch};
+use tokio_util::{sync::CancellationToken, task::TaskTracker};
 
+mod abuse;
+#[cfg(unix)]
+mod admin;
+mod compression;
 mod config;
+mod delivery;
 mod error;
+mod identity;
 mod limited;
 mod peer;
 mod proto;
+mod reassembly;
+mod routing;
 mod runtime;
+mod seal;
+#[cfg(test)]
 mod test;
 mod transport;
 
@@ -25,55 +42,170 @@ pub struct Cli {
     // Final will be /etc/routeweaver/config.toml
     #[arg(short, long, default_value = "config/latitude-7490.toml")]
     config_location: PathBuf,
+    // Runs the daemon when left out
+    #[command(subcommand)]
+    command: Option<Command>,
+}
+
+#[derive(Subcommand, Debug)]
+pub enum Command {
+    /// Generate a private key into a file only we can read
+    Keygen {
+        output: PathBuf,
+        /// Overwrite the file if it already exists
+        #[arg(short, long)]
+        force: bool,
+    },
+    /// Print the public key of a private key file, or of a key on stdin
+    Pubkey { key_file: Option<PathBuf> },
+    /// Write a complete config with a fresh identity to the config location
+    Init {
+        /// Overwrite the config if it already exists
+        #[arg(short, long)]
+        force: bool,
+    },
+    /// Have the running daemon reload its config, through the config's admin socket
+    #[cfg(unix)]
+    Reload,
+}
+
+async fn run_command(cli: &Cli, command: &Command) -> Result<(), RouteWeaverError> {
+    match command {
+        Command::Keygen { output, force } => {
+            let public_key = identity::keygen(output, *force)?;
+            println!("{}", public_key);
+        }
+        Command::Pubkey { key_file } => {
+            println!("{}", identity::pubkey(key_file.as_deref())?);
+        }
+        Command::Init { force } => {
+            let public_key = identity::init(&cli.config_location, *force)?;
+            println!("Wrote {} for {}", cli.config_location.display(), public_key);
+        }
+        #[cfg(unix)]
+        Command::Reload => {
+            let admin_socket = admin::admin_socket_location(&cli.config_location)?;
+            println!(
+                "{}",
+                admin::send_admin_command(&admin_socket, "reload").await?
+            );
+        }
+    }
+
+    Ok(())
+}
+
+/// Resolves once we are asked to stop, by SIGINT or SIGTERM
+async fn shutdown_signal() {
+    #[cfg(unix)]
+    {
+        use tokio::signal::unix::{signal, SignalKind};
+
+        let mut terminate = signal(SignalKind::terminate()).unwrap();
+
+        tokio::select! {
+            _ = tokio::signal::ctrl_c() => {}
+            _ = terminate.recv() => {}
+        }
+    }
+
+    #[cfg(not(unix))]
+    {
+        let _ = tokio::signal::ctrl_c().await;
+    }
 }
 
 #[tokio::main]
 async fn main() {
+    let cli = Cli::parse();
+
+    if let Some(command) = &cli.command {
+        if let Err(e) = run_command(&cli, command).await {
+            eprintln!("{}", e);
+            std::process::exit(1);
+        }
+
+        return;
+    }
+
     flexi_logger::Logger::try_with_str("debug")
         .unwrap()
         .start()
         .unwrap();
 
-    let cli = Cli::parse();
+    let config = match Config::load(&cli.config_location) {
+        Ok(config) => config,
+        Err(e) => {
+            log::error!("Failed to load config: {}", e);
+            return;
+        }
+    };
 
-    let config = std::fs::read_to_string(cli.config_location).unwrap();
-    let config: Config = toml::from_str(&config).unwrap();
-    let config = Arc::new(config);
-    let pre_assembled_message_tracker = Arc::new(HashCache::with_capacity(0, 1024));
-    let clear_text_message_queue = Arc::new(Queue::new());
-
-    for protocol in &config.enabled_transports {
-        match protocol {
-            #[cfg(tcp_transport)]
-            Protocol::Tcp => {
-                tokio::spawn(
-                    accept_connections_from_peers::<transport::tcp::TcpTransport>(
-                        clear_text_message_queue.clone(),
-                        pre_assembled_message_tracker.clone(),
-                        config.clone(),
-                    ),
-                );
-            }
-            #[cfg(unix_transport)]
-            Protocol::Unix => {
-                tokio::spawn(accept_connections_from_peers::<
-                    transport::unix::UnixTransport,
-                >(
-                    clear_text_message_queue.clone(),
-                    pre_assembled_message_tracker.clone(),
-                    config.clone(),
-                ));
-            }
-            Protocol::Http => todo!(),
-            #[allow(unreachable_patterns)]
-            _ => {
-                log::error!("Unsupported transport: {:?}", protocol);
-                continue;
-            }
-        };
-    }
+    let (encoded_message_sender, encoded_message_receiver) = channel(1024);
+    let (relayed_segment_sender, relayed_segment_receiver) = channel(1024);
+
+    let message_tracker = Arc::new(MessageTracker::new(config.reassembly.clone()));
+    let dictionaries = match ZstdDictionaries::load(&config.zstd_dictionaries) {
+        Ok(dictionaries) => Arc::new(dictionaries),
+        Err(e) => {
+            log::error!("Failed to load zstd dictionaries: {}", e);
+            return;
+        }
+    };
 
-    loop {
-        sleep(Duration::from_secs(100)).await;
+    let context = RuntimeContext {
+        config: Arc::new(watch::Sender::new(Arc::new(config))),
+        message_queue: Arc::new(Queue::new()),
+        message_tracker,
+        deliveries: Arc::new(DeliveryTracker::default()),
+        abuse: Arc::new(AbuseTracker::default()),
+        session_tracker: Arc::new(DashMap::new()),
+        routing_table: Arc::new(RoutingTable::default()),
+        dictionaries,
+        encoded_message_sender,
+        relayed_segment_sender,
+        shutdown: CancellationToken::new(),
+        tasks: TaskTracker::new(),
+    };
+
+    context
+        .tasks
+        .spawn(encode_clear_text_message(context.clone()));
+    context.tasks.spawn(route_encoded_message(
+        context.clone(),
+        encoded_message_receiver,
+    ));
+    context
+        .tasks
+        .spawn(relay_segments(context.clone(), relayed_segment_receiver));
+    context.tasks.spawn(advertise_routes(context.clone()));
+    context
+        .tasks
+        .spawn(expire_pre_assembled_messages(context.clone()));
+    context
+        .tasks
+        .spawn(retransmit_unacknowledged(context.clone()));
+
+    context.tasks.spawn(manage_transports(context.clone()));
+
+    #[cfg(unix)]
+    {
+        context.tasks.spawn(admin::reload_on_hangup(
+            context.clone(),
+            cli.config_location.clone(),
+        ));
+
+        if let Some(admin_socket) = context.config().admin_socket.clone() {
+            context.tasks.spawn(admin::serve_admin_socket(
+                context.clone(),
+                admin_socket,
+                cli.config_location.clone(),
+            ));
+        }
     }
+
+    shutdown_signal().await;
+    log::info!("Shutting down");
+
+    shutdown(&context).await;
 }
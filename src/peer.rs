@@ -1,31 +1,37 @@
-use std::{collections::BTreeMap, convert::Infallible, pin::Pin, sync::Arc};
+use std::{
+    sync::{Arc, Mutex},
+    time::Duration,
+};
 
 use crate::{
-    config::Config,
+    compression::CompressionPolicy,
+    error::RouteWeaverError,
     limited::LimitedVec,
     proto::{
-        Message, MessageCompressionMode, MessageSegment, PrivateKey, PublicKey,
-        BINCODE_MESSAGE_CONFIG, MAX_MESSAGE_SEGMENT_SIZE,
+        DictionaryId, Message, MessageSegment, Packet, Peer, PrivateKey, PublicKey,
+        BINCODE_MESSAGE_CONFIG, BINCODE_PACKET_CONFIG, MAX_NOISE_MESSAGE_SIZE, PROTOCOL_EDITION,
     },
-    transport::{Transport, TransportReader},
+    transport::{TransportReader, TransportWriter},
 };
-use bincode::serde::encode_to_vec;
-use blake2::{Blake2s256, Digest};
-use dashmap::DashMap;
-use deadqueue::limited::Queue;
-use futures_util::StreamExt;
-use itertools::Itertools;
+use bincode::serde::{decode_from_slice, encode_to_vec};
+use futures_util::{SinkExt, StreamExt};
 use once_cell::sync::Lazy;
-use scc::HashCache;
-use snow::{params::NoiseParams, HandshakeState, TransportState};
-use tokio::sync::mpsc::{channel, Receiver, Sender};
+use snow::{
+    params::NoiseParams,
+    resolvers::{CryptoResolver, DefaultResolver},
+    HandshakeState, TransportState,
+};
+use tokio::{sync::mpsc::Sender, time::timeout};
+use tokio_util::sync::CancellationToken;
 
-static NOISE_PROLOGUE: Lazy<String> =
-    Lazy::new(|| format!("router-weaver edition {}", env!("CARGO_PKG_VERSION_MAJOR")));
+pub static NOISE_PROLOGUE: Lazy<String> =
+    Lazy::new(|| format!("router-weaver edition {}", PROTOCOL_EDITION));
 
 static NOISE_PATTERN: Lazy<NoiseParams> =
     Lazy::new(|| "Noise_XX_25519_ChaChaPoly_BLAKE2s".parse().unwrap());
 
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+
 fn create_noise_builder<'a>() -> snow::Builder<'a> {
     snow::Builder::new(NOISE_PATTERN.clone()).prologue(NOISE_PROLOGUE.as_bytes())
 }
@@ -39,6 +45,14 @@ pub fn create_keypair() -> (PublicKey, PrivateKey) {
     )
 }
 
+/// The X25519 public half of a private key
+pub fn derive_public_key(key: &PrivateKey) -> PublicKey {
+    let mut dh = DefaultResolver.resolve_dh(&NOISE_PATTERN.dh).unwrap();
+    dh.set(&key.0);
+
+    PublicKey(dh.pubkey().try_into().unwrap())
+}
+
 fn create_responder(key: &PrivateKey) -> NoiseState {
     NoiseState::Handshake(Box::new(
         create_noise_builder()
@@ -62,3 +76,190 @@ pub enum NoiseState {
     Transport(Box<TransportState>),
 }
 
+/// Drive the three message XX handshake over a freshly opened connection
+///
+/// Returns the remote static key along with the session to use for every segment afterwards
+pub async fn perform_handshake(
+    reader: &mut impl TransportReader,
+    writer: &mut impl TransportWriter,
+    key: &PrivateKey,
+    initiator: bool,
+    dictionaries: &[DictionaryId],
+    max_message_size: u64,
+) -> Result<(PublicKey, Session), RouteWeaverError> {
+    let state = if initiator {
+        create_initiator(key)
+    } else {
+        create_responder(key)
+    };
+
+    timeout(
+        HANDSHAKE_TIMEOUT,
+        drive_handshake(reader, writer, state, dictionaries, max_message_size),
+    )
+    .await
+    .map_err(|_| RouteWeaverError::Handshake)?
+}
+
+async fn drive_handshake(
+    reader: &mut impl TransportReader,
+    writer: &mut impl TransportWriter,
+    mut state: NoiseState,
+    dictionaries: &[DictionaryId],
+    max_message_size: u64,
+) -> Result<(PublicKey, Session), RouteWeaverError> {
+    // Every handshake message carries our capabilities as its payload
+    let payload = encode_to_vec(
+        Message::Handshake {
+            dictionaries: dictionaries.to_vec(),
+            max_message_size,
+        },
+        BINCODE_MESSAGE_CONFIG,
+    )
+    .map_err(|_| RouteWeaverError::PacketEncoding)?;
+    let mut buffer = vec![0; MAX_NOISE_MESSAGE_SIZE];
+    let mut remote_dictionaries = Vec::new();
+    let mut remote_max_message_size = 0;
+
+    loop {
+        state = match state {
+            NoiseState::Handshake(mut handshake) => {
+                if handshake.is_handshake_finished() {
+                    NoiseState::Transport(Box::new(handshake.into_transport_mode()?))
+                } else if handshake.is_my_turn() {
+                    let length = handshake.write_message(&payload, &mut buffer)?;
+
+                    writer
+                        .send(Packet {
+                            source: PublicKey::UNKNOWN,
+                            destination: PublicKey::UNKNOWN,
+                            message: MessageSegment::Handshake {
+                                data: LimitedVec(buffer[..length].to_vec()),
+                            },
+                        })
+                        .await?;
+
+                    NoiseState::Handshake(handshake)
+                } else {
+                    let Some(packet) = reader.next().await else {
+                        return Err(RouteWeaverError::Handshake);
+                    };
+
+                    let MessageSegment::Handshake { data } = packet?.message else {
+                        return Err(RouteWeaverError::Handshake);
+                    };
+
+                    let length = handshake.read_message(&data.0, &mut buffer)?;
+
+                    let Ok((
+                        Message::Handshake {
+                            dictionaries,
+                            max_message_size,
+                        },
+                        _,
+                    )) = decode_from_slice(&buffer[..length], BINCODE_MESSAGE_CONFIG)
+                    else {
+                        return Err(RouteWeaverError::Handshake);
+                    };
+                    remote_dictionaries = dictionaries;
+                    remote_max_message_size = max_message_size;
+
+                    NoiseState::Handshake(handshake)
+                }
+            }
+            NoiseState::Transport(transport) => {
+                let remote_key = transport
+                    .get_remote_static()
+                    .and_then(|key| key.try_into().ok())
+                    .map(PublicKey)
+                    .ok_or(RouteWeaverError::Handshake)?;
+
+                return Ok((
+                    remote_key,
+                    Session {
+                        noise: Mutex::new(*transport),
+                        remote_key,
+                        remote_dictionaries,
+                        remote_max_message_size,
+                    },
+                ));
+            }
+        };
+    }
+}
+
+/// Established Noise session with a directly connected peer
+pub struct Session {
+    noise: Mutex<TransportState>,
+    // The only source the session itself vouches for
+    pub remote_key: PublicKey,
+    // What the peer told us it can decompress with during the handshake
+    pub remote_dictionaries: Vec<DictionaryId>,
+    pub remote_max_message_size: u64,
+}
+
+impl Session {
+    pub fn encrypt_segment(
+        &self,
+        segment: &MessageSegment,
+    ) -> Result<MessageSegment, RouteWeaverError> {
+        let segment = encode_to_vec(segment, BINCODE_PACKET_CONFIG)
+            .map_err(|_| RouteWeaverError::PacketEncoding)?;
+        let mut buffer = vec![0; MAX_NOISE_MESSAGE_SIZE];
+
+        let length = self
+            .noise
+            .lock()
+            .unwrap()
+            .write_message(&segment, &mut buffer)?;
+        buffer.truncate(length);
+
+        Ok(MessageSegment::Encrypted {
+            data: LimitedVec(buffer),
+        })
+    }
+
+    pub fn decrypt_segment(
+        &self,
+        segment: MessageSegment,
+    ) -> Result<MessageSegment, RouteWeaverError> {
+        let MessageSegment::Encrypted { data } = segment else {
+            return Err(RouteWeaverError::UnexpectedSegment);
+        };
+
+        let mut buffer = vec![0; MAX_NOISE_MESSAGE_SIZE];
+        let length = self
+            .noise
+            .lock()
+            .unwrap()
+            .read_message(&data.0, &mut buffer)?;
+
+        let (segment, _) = decode_from_slice(&buffer[..length], BINCODE_PACKET_CONFIG)?;
+
+        match segment {
+            MessageSegment::Message { .. }
+            | MessageSegment::EndMessage { .. }
+            | MessageSegment::Acknowledge { .. }
+            | MessageSegment::MissingSegments { .. } => Ok(segment),
+            // Nesting session frames inside each other makes no sense
+            _ => Err(RouteWeaverError::UnexpectedSegment),
+        }
+    }
+}
+
+/// Handle through which other tasks can hand packets to a connected peer
+#[derive(Clone)]
+pub struct ConnectedPeer {
+    pub packet_sender: Sender<Packet>,
+    // Largest segment the transport underneath would like to carry
+    pub segment_size: usize,
+    pub compression: Arc<CompressionPolicy>,
+    // Zstd dictionary both sides hold, if any
+    pub dictionary: Option<DictionaryId>,
+    // Largest message the peer takes delivery of itself, relaying is not bound by it
+    pub max_message_size: usize,
+    // Where the peer is reachable, when the transport tells us
+    pub peer: Option<Peer>,
+    // Cancelling it hangs up on the peer
+    pub disconnect: CancellationToken,
+}
@@ -9,20 +9,47 @@ use itertools::Itertools;
 use serde::{Deserialize, Serialize};
 
 use std::{
-    collections::HashSet, fmt::Display, mem::size_of, net::IpAddr, num::NonZeroU8, str::FromStr,
+    collections::{BTreeMap, HashSet},
+    fmt::{Debug, Display},
+    mem::size_of,
+    net::{IpAddr, SocketAddr},
+    num::NonZeroU32,
+    path::PathBuf,
+    str::FromStr,
     time::Duration,
 };
-use zeroize::{Zeroize, ZeroizeOnDrop};
+use zeroize::{Zeroize, ZeroizeOnDrop, Zeroizing};
 
-pub const MAX_MESSAGE_SEGMENT_SIZE: usize = 63 * Unit::KiB.as_bits_u128() as usize;
+// byte_unit only hands out sizes in bits
+const KIB: usize = Unit::KiB.as_bits_u128() as usize / 8;
+const MIB: usize = Unit::MiB.as_bits_u128() as usize / 8;
+
+pub const MAX_MESSAGE_SEGMENT_SIZE: usize = 63 * KIB;
+// Ceiling for max_decompressed_message_size, which is what actually bounds a whole message
+pub const MAX_DECOMPRESSED_MESSAGE_SIZE: usize = 256 * MIB;
+// Noise refuses to produce or accept anything larger than this
+pub const MAX_NOISE_MESSAGE_SIZE: usize = 65535;
+// Most segments asked for at once, the rest are asked for on the next round
+pub const MAX_MISSING_SEGMENTS: usize = 4096;
 // Estimated size of a serialized packet
-pub const MAX_SERIALIZED_PACKET_SIZE: usize =
-    (size_of::<PublicKey>() * 2) + 64 * Unit::KiB.as_bits_u128() as usize + 100;
+pub const MAX_SERIALIZED_PACKET_SIZE: usize = (size_of::<PublicKey>() * 2) + 64 * KIB + 100;
+
+// Peers only understand each other within the same major version
+pub const PROTOCOL_EDITION: u8 = match u8::from_str_radix(env!("CARGO_PKG_VERSION_MAJOR"), 10) {
+    Ok(edition) => edition,
+    Err(_) => panic!("Major version does not fit in a byte"),
+};
 
 #[derive(Debug, Clone, PartialEq, Eq, Hash, Serialize, Deserialize)]
 #[non_exhaustive]
 pub enum Address {
     Ip(IpAddr),
+    // For when the peer isn't listening on the transport's usual port
+    Socket(SocketAddr),
+    // Nickname on whatever IRC network the transport is connected to
+    Nick(String),
+    // Filesystem path of a socket
+    Path(PathBuf),
 }
 
 #[derive(Debug, Copy, Clone, PartialEq, Eq, Hash, Serialize, Deserialize, Zeroize)]
@@ -32,6 +59,7 @@ pub enum Protocol {
     Unix,
     Http,
     Bluetooth,
+    Irc,
 }
 
 impl Display for Protocol {
@@ -41,6 +69,7 @@ impl Display for Protocol {
             Protocol::Unix => "unix",
             Protocol::Http => "http",
             Protocol::Bluetooth => "bluetooth",
+            Protocol::Irc => "irc",
         })
     }
 }
@@ -59,6 +88,9 @@ impl Display for Peer {
             self.protocol,
             match &self.address {
                 Address::Ip(ip) => ip.to_string(),
+                Address::Socket(socket) => socket.to_string(),
+                Address::Nick(nick) => nick.clone(),
+                Address::Path(path) => path.display().to_string(),
             }
         )?;
 
@@ -74,19 +106,47 @@ impl FromStr for Peer {
             return Err(RouteWeaverError::PeerAddress);
         };
 
+        // An IP on its own means whatever port the transport is configured for
+        let parse_ip_address = |address: &str| {
+            address
+                .parse()
+                .map(Address::Ip)
+                .or_else(|_| address.parse().map(Address::Socket))
+                .map_err(|_| RouteWeaverError::PeerAddress)
+        };
+
         match protocol.to_lowercase().as_str() {
             "tcp" => Ok(Peer {
                 protocol: Protocol::Tcp,
-                address: Address::Ip(address.parse().map_err(|_| RouteWeaverError::PeerAddress)?),
+                address: parse_ip_address(address)?,
+            }),
+            "http" => Ok(Peer {
+                protocol: Protocol::Http,
+                address: parse_ip_address(address)?,
+            }),
+            "unix" if !address.is_empty() => Ok(Peer {
+                protocol: Protocol::Unix,
+                address: Address::Path(PathBuf::from(address)),
+            }),
+            "irc" if !address.is_empty() && !address.contains(char::is_whitespace) => Ok(Peer {
+                protocol: Protocol::Irc,
+                address: Address::Nick(address.to_string()),
             }),
             _ => Err(RouteWeaverError::PeerAddress),
         }
     }
 }
 
-#[derive(Serialize, Deserialize, Debug, ZeroizeOnDrop)]
+#[derive(Serialize, Deserialize, ZeroizeOnDrop)]
 pub struct PrivateKey(pub [u8; 32]);
 
+// Anything holding the key can be logged without giving it away
+impl Debug for PrivateKey {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str("PrivateKey(<redacted>)")
+    }
+}
+
 impl Display for PrivateKey {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         f.write_str(&HEXLOWER_PERMISSIVE.encode(&self.0))
@@ -97,10 +157,16 @@ impl FromStr for PrivateKey {
     type Err = RouteWeaverError;
 
     fn from_str(s: &str) -> Result<Self, Self::Err> {
-        Ok(PrivateKey(
+        // Don't leave a copy of the key behind in the decode buffer
+        let decoded = Zeroizing::new(
             HEXLOWER_PERMISSIVE
                 .decode(s.as_bytes())
-                .map_err(|_| RouteWeaverError::KeyParsingError)?
+                .map_err(|_| RouteWeaverError::KeyParsingError)?,
+        );
+
+        Ok(PrivateKey(
+            decoded
+                .as_slice()
                 .try_into()
                 .map_err(|_| RouteWeaverError::KeyParsingError)?,
         ))
@@ -112,6 +178,11 @@ impl FromStr for PrivateKey {
 )]
 pub struct PublicKey(pub [u8; 32]);
 
+impl PublicKey {
+    /// Placeholder for packets sent before the remote identity is known
+    pub const UNKNOWN: PublicKey = PublicKey([0; 32]);
+}
+
 impl Display for PublicKey {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         f.write_str(&HEXLOWER_PERMISSIVE.encode(&self.0))
@@ -132,7 +203,7 @@ impl FromStr for PublicKey {
     }
 }
 
-#[derive(Serialize, Deserialize, Debug)]
+#[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct Packet {
     pub source: PublicKey,
     pub destination: PublicKey,
@@ -142,32 +213,98 @@ pub struct Packet {
 #[derive(Serialize, Deserialize, Debug, Hash, PartialEq, Eq, PartialOrd, Ord, Clone, Zeroize)]
 pub struct ApplicationId(pub ArrayString<8>);
 
-#[derive(Serialize, Deserialize, Debug)]
+#[derive(Serialize, Deserialize, Debug, Clone)]
 pub enum MessageSegment {
+    // Indexes are varints on the wire, so small messages don't pay for the large ones
     Message {
-        index: u8,
+        id: MessageId,
+        index: u32,
         data: LimitedVec<u8, MAX_MESSAGE_SEGMENT_SIZE>,
     },
     EndMessage {
-        compression_mode: Option<MessageCompressionMode>,
-        total_indexes: NonZeroU8,
+        id: MessageId,
+        total_indexes: NonZeroU32,
         hash: [u8; 32],
+        // The destination answers with an acknowledgement or the segments it is missing
+        reliable: bool,
+    },
+    // The destination has a reliable message in full
+    Acknowledge {
+        id: MessageId,
+        // MAC only the two ends of the message can produce, as relays could forge the reply otherwise
+        tag: [u8; 32],
+    },
+    // Segments of a reliable message the destination is still waiting on
+    MissingSegments {
+        id: MessageId,
+        indexes: LimitedVec<u32, MAX_MISSING_SEGMENTS>,
+        tag: [u8; 32],
+    },
+    // A raw Noise handshake message
+    Handshake {
+        data: LimitedVec<u8, MAX_NOISE_MESSAGE_SIZE>,
+    },
+    // A Message or EndMessage encrypted with the session between two directly connected peers
+    Encrypted {
+        data: LimitedVec<u8, MAX_NOISE_MESSAGE_SIZE>,
     },
 }
 
-#[derive(Serialize, Deserialize, Debug)]
+/// Tells apart the messages in flight between the same two nodes
+#[derive(Serialize, Deserialize, Debug, Hash, PartialEq, Eq, Clone, Copy)]
+pub struct MessageId(pub u64);
+
+impl MessageId {
+    pub fn random() -> Self {
+        Self(rand::random())
+    }
+}
+
+impl Display for MessageId {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{:016x}", self.0)
+    }
+}
+
+/// Blake2s hash of a shared zstd dictionary, so peers can tell whether they hold the same one
+#[derive(Serialize, Deserialize, Debug, Hash, PartialEq, Eq, PartialOrd, Ord, Clone, Copy)]
+pub struct DictionaryId(pub [u8; 32]);
+
+impl Display for DictionaryId {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(&HEXLOWER_PERMISSIVE.encode(&self.0))
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
 pub enum MessageCompressionMode {
     Lz4,
-    Zlib
+    Zlib,
+    Zstd { dictionary: Option<DictionaryId> },
+}
+
+/// Leads a sealed message, readable by its destination alone
+#[derive(Serialize, Deserialize, Debug)]
+pub struct SealedHeader {
+    // Relays have no business knowing how a message was compressed either
+    pub compression_mode: Option<MessageCompressionMode>,
+    pub length: u64,
 }
 
 #[derive(Serialize, Deserialize, Debug)]
 pub enum Message {
     Denied,
-    Handshake,
+    Handshake {
+        // Dictionaries the sender is able to decompress with
+        dictionaries: Vec<DictionaryId>,
+        // Largest encoded message the sender will take delivery of
+        max_message_size: u64,
+    },
     RequestPeersList,
     PeersList {
         peers: HashSet<Peer>,
+        // Keys reachable through the sender and how many hops away from it they are
+        routes: BTreeMap<PublicKey, u8>,
     },
     RequestSystemInformation,
     SystemInformation {
@@ -177,6 +314,8 @@ pub enum Message {
     ApplicationAdvertisement {
         applications: HashSet<ApplicationId>,
     },
+    // The sender is shutting down and will hang up shortly
+    Goodbye,
 }
 
 pub const BINCODE_PACKET_CONFIG: Configuration<
@@ -188,6 +327,16 @@ pub const BINCODE_PACKET_CONFIG: Configuration<
     .with_variable_int_encoding()
     .with_limit::<MAX_SERIALIZED_PACKET_SIZE>();
 
+// Whole messages, which are streamed in over as many segments as they take
+pub const BINCODE_DECOMPRESSED_MESSAGE_CONFIG: Configuration<
+    BigEndian,
+    Varint,
+    Limit<MAX_DECOMPRESSED_MESSAGE_SIZE>,
+> = bincode::config::standard()
+    .with_big_endian()
+    .with_variable_int_encoding()
+    .with_limit::<MAX_DECOMPRESSED_MESSAGE_SIZE>();
+
 pub const BINCODE_MESSAGE_CONFIG: Configuration<
     BigEndian,
     Varint,
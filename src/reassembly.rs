@@ -0,0 +1,393 @@
+use crate::{
+    limited::LimitedVec,
+    proto::{MessageId, PublicKey, MAX_MESSAGE_SEGMENT_SIZE, MAX_MISSING_SEGMENTS},
+};
+use blake2::{Blake2s256, Digest};
+use serde::{Deserialize, Serialize};
+use serde_with::{serde_as, DurationSeconds};
+use std::{
+    collections::{BTreeMap, HashMap},
+    fmt::Display,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Mutex,
+    },
+    time::{Duration, Instant},
+};
+
+const MIB: usize = 1024 * 1024;
+
+pub type MessageSegmentData = LimitedVec<u8, MAX_MESSAGE_SEGMENT_SIZE>;
+
+// Source, destination and identifier of the message being pieced back together
+pub type MessageKey = (PublicKey, PublicKey, MessageId);
+
+/// How much half finished data we are willing to sit on
+#[serde_as]
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct ReassemblyLimits {
+    // A message that hasn't finished by then is abandoned
+    #[serde_as(as = "DurationSeconds<u64>")]
+    pub message_timeout: Duration,
+    // Charged to the neighbor that delivered the segments, as the claimed source is not vouched for
+    pub max_bytes_per_source: usize,
+    pub max_total_bytes: usize,
+    pub max_messages: usize,
+}
+
+impl Default for ReassemblyLimits {
+    fn default() -> Self {
+        Self {
+            message_timeout: Duration::from_secs(60),
+            // Comfortably fits the largest message we can segment
+            max_bytes_per_source: 32 * MIB,
+            max_total_bytes: 256 * MIB,
+            max_messages: 1024,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+enum EvictionReason {
+    TimedOut,
+    NeighborBudget,
+    GlobalCap,
+}
+
+impl Display for EvictionReason {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            EvictionReason::TimedOut => write!(f, "it timed out"),
+            EvictionReason::NeighborBudget => {
+                write!(f, "the neighbor that delivered it ran over budget")
+            }
+            EvictionReason::GlobalCap => write!(f, "the global reassembly cap was reached"),
+        }
+    }
+}
+
+#[derive(Debug)]
+struct PendingMessage {
+    // Every segment before `next_index`, back to back
+    data: Vec<u8>,
+    // Hash of `data` so far
+    hasher: Blake2s256,
+    next_index: u32,
+    // Segments that showed up ahead of their turn
+    out_of_order: BTreeMap<u32, MessageSegmentData>,
+    bytes: usize,
+    // Bytes held on behalf of each neighbor that delivered part of this message
+    charged: HashMap<PublicKey, usize>,
+    started: Instant,
+}
+
+impl PendingMessage {
+    fn new(started: Instant) -> Self {
+        Self {
+            data: Vec::new(),
+            hasher: Blake2s256::default(),
+            next_index: 0,
+            out_of_order: BTreeMap::new(),
+            bytes: 0,
+            charged: HashMap::new(),
+            started,
+        }
+    }
+
+    fn append(&mut self, segment: &[u8]) {
+        self.hasher.update(segment);
+        self.data.extend_from_slice(segment);
+        self.next_index += 1;
+    }
+
+    /// Take a segment in, false when it duplicates one we already had
+    fn insert(&mut self, neighbor: PublicKey, index: u32, segment: MessageSegmentData) -> bool {
+        // Already part of the hash, so there is no replacing it
+        if index < self.next_index || self.out_of_order.contains_key(&index) {
+            return false;
+        }
+
+        let length = segment.0.len();
+        self.bytes += length;
+        *self.charged.entry(neighbor).or_default() += length;
+
+        if index > self.next_index {
+            self.out_of_order.insert(index, segment);
+            return true;
+        }
+
+        self.append(&segment.0);
+
+        // Whatever was waiting on this one can follow it now
+        while let Some(next) = self.out_of_order.remove(&self.next_index) {
+            self.append(&next.0);
+        }
+
+        true
+    }
+}
+
+/// Everything received of a message once its end has arrived
+#[derive(Debug)]
+pub struct ReassembledMessage {
+    pub data: Vec<u8>,
+    pub hash: [u8; 32],
+    // Segments that made it into `data`
+    pub segments: u32,
+    // Segments left stranded behind a gap
+    pub stray_segments: usize,
+}
+
+#[derive(Debug, Default)]
+struct TrackerState {
+    messages: HashMap<MessageKey, PendingMessage>,
+    neighbor_bytes: HashMap<PublicKey, usize>,
+    total_bytes: usize,
+    // Reliable messages handed over recently, so a repeated end only gets acknowledged again
+    delivered: HashMap<MessageKey, Instant>,
+}
+
+impl TrackerState {
+    fn remove(&mut self, key: &MessageKey) -> Option<PendingMessage> {
+        let message = self.messages.remove(key)?;
+
+        self.total_bytes -= message.bytes;
+        for (neighbor, charged) in &message.charged {
+            if let Some(bytes) = self.neighbor_bytes.get_mut(neighbor) {
+                *bytes -= charged;
+
+                if *bytes == 0 {
+                    self.neighbor_bytes.remove(neighbor);
+                }
+            }
+        }
+
+        Some(message)
+    }
+
+    fn oldest(&self, filter: impl Fn(&PendingMessage) -> bool) -> Option<MessageKey> {
+        self.messages
+            .iter()
+            .filter(|(_, message)| filter(message))
+            .min_by_key(|(_, message)| message.started)
+            .map(|(key, _)| *key)
+    }
+}
+
+/// Messages whose end has not arrived yet, kept within fixed memory bounds
+///
+/// In order segments are hashed and appended as they come, only the ones ahead of a gap are held apart
+#[derive(Debug, Default)]
+pub struct MessageTracker {
+    limits: ReassemblyLimits,
+    state: Mutex<TrackerState>,
+    evictions: AtomicU64,
+}
+
+impl MessageTracker {
+    pub fn new(limits: ReassemblyLimits) -> Self {
+        Self {
+            limits,
+            ..Default::default()
+        }
+    }
+
+    /// Store a segment delivered by `neighbor`, evicting whatever has to go to keep us within our limits
+    pub fn insert_segment(
+        &self,
+        key: MessageKey,
+        neighbor: PublicKey,
+        index: u32,
+        data: MessageSegmentData,
+    ) {
+        let mut state = self.state.lock().unwrap();
+        let state = &mut *state;
+        let now = Instant::now();
+
+        if state
+            .messages
+            .get(&key)
+            .is_some_and(|message| now - message.started > self.limits.message_timeout)
+        {
+            self.evict(state, &key, EvictionReason::TimedOut);
+        }
+
+        let length = data.0.len();
+
+        // Only an empty message is segmented into an empty segment, which is always its first,
+        // so one ahead of a gap would take up room without ever being charged for it
+        if length == 0
+            && index
+                > state
+                    .messages
+                    .get(&key)
+                    .map_or(0, |message| message.next_index)
+        {
+            log::warn!(
+                "Dropping empty message segment from {} ahead of its turn",
+                neighbor
+            );
+            return;
+        }
+
+        // Only absurdly tight limits could make a single segment too large
+        if length > self.limits.max_bytes_per_source || length > self.limits.max_total_bytes {
+            log::warn!(
+                "Dropping message segment from {} larger than our limits",
+                neighbor
+            );
+            self.evictions.fetch_add(1, Ordering::Relaxed);
+            return;
+        }
+
+        // Make room among what this neighbor delivered first, whatever sources it claimed
+        while state.neighbor_bytes.get(&neighbor).copied().unwrap_or(0) + length
+            > self.limits.max_bytes_per_source
+        {
+            let Some(oldest) = state.oldest(|message| message.charged.contains_key(&neighbor))
+            else {
+                break;
+            };
+
+            self.evict(state, &oldest, EvictionReason::NeighborBudget);
+        }
+
+        while state.total_bytes + length > self.limits.max_total_bytes
+            || (!state.messages.contains_key(&key)
+                && state.messages.len() >= self.limits.max_messages)
+        {
+            let Some(oldest) = state.oldest(|_| true) else {
+                break;
+            };
+
+            self.evict(state, &oldest, EvictionReason::GlobalCap);
+        }
+
+        let message = state
+            .messages
+            .entry(key)
+            .or_insert_with(|| PendingMessage::new(now));
+
+        if !message.insert(neighbor, index, data) {
+            log::warn!("Received duplicate message segment from: {}", neighbor);
+            return;
+        }
+
+        *state.neighbor_bytes.entry(neighbor).or_default() += length;
+        state.total_bytes += length;
+    }
+
+    /// Hand over a message along with the hash of everything that arrived in order
+    pub fn take(&self, key: &MessageKey) -> Option<ReassembledMessage> {
+        let mut state = self.state.lock().unwrap();
+        let message = state.remove(key)?;
+
+        if message.started.elapsed() > self.limits.message_timeout {
+            self.record_eviction(key, EvictionReason::TimedOut);
+            return None;
+        }
+
+        Some(ReassembledMessage {
+            data: message.data,
+            hash: message.hasher.finalize().into(),
+            segments: message.next_index,
+            stray_segments: message.out_of_order.len(),
+        })
+    }
+
+    /// Indexes below `total_indexes` we have yet to receive, as many as fit in one request
+    pub fn missing_segments(&self, key: &MessageKey, total_indexes: u32) -> Vec<u32> {
+        let state = self.state.lock().unwrap();
+        let message = state.messages.get(key);
+
+        (message.map_or(0, |message| message.next_index)..total_indexes)
+            .filter(|index| {
+                !message.is_some_and(|message| message.out_of_order.contains_key(index))
+            })
+            .take(MAX_MISSING_SEGMENTS)
+            .collect()
+    }
+
+    /// Remember a reliable message was handed over, for as long as its sender might ask again
+    pub fn mark_delivered(&self, key: MessageKey) {
+        let mut state = self.state.lock().unwrap();
+
+        if state.delivered.len() >= self.limits.max_messages {
+            if let Some(oldest) = state
+                .delivered
+                .iter()
+                .min_by_key(|(_, delivered)| **delivered)
+                .map(|(key, _)| *key)
+            {
+                state.delivered.remove(&oldest);
+            }
+        }
+
+        state.delivered.insert(key, Instant::now());
+    }
+
+    pub fn was_delivered(&self, key: &MessageKey) -> bool {
+        self.state.lock().unwrap().delivered.contains_key(key)
+    }
+
+    /// Drop every message that has been waiting on its end for too long
+    pub fn expire(&self) {
+        let mut state = self.state.lock().unwrap();
+        let state = &mut *state;
+
+        state
+            .delivered
+            .retain(|_, delivered| delivered.elapsed() <= self.limits.message_timeout);
+
+        let expired = state
+            .messages
+            .iter()
+            .filter(|(_, message)| message.started.elapsed() > self.limits.message_timeout)
+            .map(|(key, _)| *key)
+            .collect::<Vec<_>>();
+
+        for key in expired {
+            self.evict(state, &key, EvictionReason::TimedOut);
+        }
+    }
+
+    /// Largest message that fits within our limits on its own
+    pub fn max_message_size(&self) -> usize {
+        self.limits
+            .max_bytes_per_source
+            .min(self.limits.max_total_bytes)
+    }
+
+    /// Total number of messages abandoned so far
+    pub fn evictions(&self) -> u64 {
+        self.evictions.load(Ordering::Relaxed)
+    }
+
+    pub fn pending_bytes(&self) -> usize {
+        self.state.lock().unwrap().total_bytes
+    }
+
+    pub fn pending_messages(&self) -> usize {
+        self.state.lock().unwrap().messages.len()
+    }
+
+    fn evict(&self, state: &mut TrackerState, key: &MessageKey, reason: EvictionReason) {
+        if state.remove(key).is_some() {
+            self.record_eviction(key, reason);
+        }
+    }
+
+    fn record_eviction(&self, key: &MessageKey, reason: EvictionReason) {
+        let evictions = self.evictions.fetch_add(1, Ordering::Relaxed) + 1;
+
+        log::warn!(
+            "Abandoned message {} from {} to {} because {} ({} evictions so far)",
+            key.2,
+            key.0,
+            key.1,
+            reason,
+            evictions
+        );
+    }
+}
@@ -0,0 +1,95 @@
+use crate::{peer::ConnectedPeer, proto::PublicKey};
+use dashmap::DashMap;
+use std::collections::{BTreeMap, HashMap};
+
+// Anything further away than this is treated as unreachable so stale routes can't count upwards forever
+pub const MAX_HOPS: u8 = 16;
+
+/// Forwarding table learned from the routes our direct peers advertise
+#[derive(Default, Debug)]
+pub struct RoutingTable {
+    // Destination -> next hop -> hops from us
+    routes: DashMap<PublicKey, HashMap<PublicKey, u8>>,
+}
+
+impl RoutingTable {
+    /// Replace everything we know through `next_hop` with what it just advertised
+    pub fn update_routes_via(
+        &self,
+        my_public_key: PublicKey,
+        next_hop: PublicKey,
+        advertised: &BTreeMap<PublicKey, u8>,
+    ) {
+        self.remove_next_hop(next_hop);
+
+        for (destination, hops) in advertised {
+            if *destination == my_public_key || *destination == next_hop {
+                continue;
+            }
+
+            let hops = hops.saturating_add(1);
+            if hops > MAX_HOPS {
+                continue;
+            }
+
+            self.routes
+                .entry(*destination)
+                .or_default()
+                .insert(next_hop, hops);
+        }
+    }
+
+    pub fn remove_next_hop(&self, next_hop: PublicKey) {
+        self.routes.retain(|_, next_hops| {
+            next_hops.remove(&next_hop);
+            !next_hops.is_empty()
+        });
+    }
+
+    /// Pick the connected peer closest to the destination
+    pub fn next_hop(
+        &self,
+        destination: PublicKey,
+        sessions: &DashMap<PublicKey, ConnectedPeer>,
+    ) -> Option<PublicKey> {
+        if sessions.contains_key(&destination) {
+            return Some(destination);
+        }
+
+        self.routes
+            .get(&destination)?
+            .iter()
+            .filter(|(next_hop, _)| sessions.contains_key(next_hop))
+            .min_by_key(|(_, hops)| **hops)
+            .map(|(next_hop, _)| *next_hop)
+    }
+
+    /// Routes to advertise to a neighbor, leaving out whatever we learned from it in the first place
+    pub fn advertisement_for(
+        &self,
+        neighbor: PublicKey,
+        sessions: &DashMap<PublicKey, ConnectedPeer>,
+    ) -> BTreeMap<PublicKey, u8> {
+        let mut advertised = BTreeMap::new();
+
+        for route in self.routes.iter() {
+            let best = route
+                .value()
+                .iter()
+                .filter(|(next_hop, _)| **next_hop != neighbor && sessions.contains_key(next_hop))
+                .map(|(_, hops)| *hops)
+                .min();
+
+            if let Some(hops) = best {
+                advertised.insert(*route.key(), hops);
+            }
+        }
+
+        for session in sessions.iter() {
+            advertised.insert(*session.key(), 1);
+        }
+
+        advertised.remove(&neighbor);
+        advertised
+    }
+}
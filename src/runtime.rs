@@ -1,209 +1,1583 @@
-use bincode::serde::encode_to_vec;
+use bincode::serde::{decode_from_slice, encode_to_vec};
 use blake2::{Blake2s256, Digest};
 use dashmap::DashMap;
 use deadqueue::unlimited::Queue;
-use entropy::shannon_entropy;
-use futures_util::StreamExt;
+use futures_util::{SinkExt, StreamExt};
 use itertools::Itertools;
-use scc::HashCache;
+use miniz_oxide::inflate::TINFLStatus;
 use std::{
-    io::Cursor,
-    pin::{pin, Pin},
+    collections::{HashMap, HashSet},
+    num::NonZeroU32,
+    path::Path,
     sync::Arc,
+    time::Duration,
 };
-use tokio::sync::mpsc::{channel, Receiver, Sender};
+use tokio::{
+    sync::{
+        mpsc::{channel, Receiver, Sender},
+        oneshot, watch,
+    },
+    task::JoinHandle,
+    time::{sleep, timeout},
+};
+use tokio_util::{sync::CancellationToken, task::TaskTracker};
 
 use crate::{
-    config::Config,
+    abuse::{AbuseTracker, Offense},
+    compression::{CompressionPolicy, ZstdDictionaries},
+    config::{Config, TransportConfig},
+    delivery::{DeliveryConfirmation, DeliveryTracker},
+    error::RouteWeaverError,
     limited::LimitedVec,
+    peer::{perform_handshake, ConnectedPeer, Session},
     proto::{
-        Message, MessageCompressionMode, MessageSegment, PublicKey, BINCODE_MESSAGE_CONFIG,
+        Address, DictionaryId, Message, MessageCompressionMode, MessageId, MessageSegment, Packet,
+        Peer, PrivateKey, Protocol, PublicKey, BINCODE_DECOMPRESSED_MESSAGE_CONFIG,
         MAX_MESSAGE_SEGMENT_SIZE,
     },
-    transport::{Transport, TransportReader},
+    reassembly::MessageTracker,
+    routing::RoutingTable,
+    seal::{open, seal, tag_reply, verify_reply},
+    transport::{Transport, TransportReader, TransportWriter},
 };
 
-pub fn determine_compression_for_data(data: &[u8]) -> Option<MessageCompressionMode> {
-    if shannon_entropy(data) > 0.5 {
-        None
-    } else {
-        Some(MessageCompressionMode::Lz4)
-    }
-}
-
 #[derive(Debug)]
 pub struct ClearTextMessage {
     pub destination: PublicKey,
     pub message: Message,
+    // Asks for reliable delivery, answered once the destination acknowledges the message
+    pub confirmation: Option<DeliveryConfirmation>,
 }
 
-pub async fn encode_clear_text_message(
-    my_public_key: PublicKey,
-    message_queue: Arc<Queue<ClearTextMessage>>,
-    message_sender: Sender<EncodedMessage>,
-) {
+pub fn compress_message(
+    message: &Message,
+    policy: &CompressionPolicy,
+    dictionary: Option<(DictionaryId, &[u8])>,
+) -> Result<(Option<MessageCompressionMode>, Vec<u8>), RouteWeaverError> {
+    let message = encode_to_vec(message, BINCODE_DECOMPRESSED_MESSAGE_CONFIG)
+        .map_err(|_| RouteWeaverError::PacketEncoding)?;
+
+    Ok(policy.compress(message, dictionary))
+}
+
+/// Compress a message of ours and seal it so only its destination can read it
+pub fn seal_message(
+    context: &RuntimeContext,
+    destination: PublicKey,
+    message: &Message,
+    policy: &CompressionPolicy,
+    dictionary: Option<(DictionaryId, &[u8])>,
+) -> Result<Vec<u8>, RouteWeaverError> {
+    let (compression_mode, data) = compress_message(message, policy, dictionary)?;
+
+    // Even a neighbor's messages are sealed, so they look no different from relayed ones
+    seal(
+        &context.config().private_key,
+        &destination,
+        compression_mode,
+        &data,
+    )
+}
+
+/// Split an encoded message into segments followed by the end message that seals them
+pub fn segment_message(
+    source: PublicKey,
+    destination: PublicKey,
+    message: &[u8],
+    segment_size: usize,
+    reliable: bool,
+) -> Result<Vec<Packet>, RouteWeaverError> {
+    let segment_size = segment_size.clamp(1, MAX_MESSAGE_SEGMENT_SIZE);
+
+    // Even an empty message needs one segment to carry it
+    let chunks = if message.is_empty() {
+        vec![message]
+    } else {
+        message.chunks(segment_size).collect_vec()
+    };
+
+    let total_indexes = u32::try_from(chunks.len())
+        .ok()
+        .and_then(NonZeroU32::new)
+        .ok_or(RouteWeaverError::MessageTooLargeForPath {
+            size: message.len(),
+            limit: segmented_size_limit(segment_size),
+        })?;
+
+    let id = MessageId::random();
+    let mut hasher = Blake2s256::default();
+    let mut packets = Vec::with_capacity(chunks.len() + 1);
+
+    for (index, chunk) in chunks.into_iter().enumerate() {
+        hasher.update(chunk);
+
+        packets.push(Packet {
+            source,
+            destination,
+            message: MessageSegment::Message {
+                id,
+                index: index as u32,
+                data: LimitedVec(chunk.to_vec()),
+            },
+        });
+    }
+
+    packets.push(Packet {
+        source,
+        destination,
+        message: MessageSegment::EndMessage {
+            id,
+            total_indexes,
+            hash: hasher.finalize().into(),
+            reliable,
+        },
+    });
+
+    Ok(packets)
+}
+
+/// Most a message can weigh once cut into segments of `segment_size`
+fn segmented_size_limit(segment_size: usize) -> usize {
+    segment_size
+        .clamp(1, MAX_MESSAGE_SEGMENT_SIZE)
+        .saturating_mul(u32::MAX as usize)
+}
+
+/// Largest message that can take a path, past the next hop only the destination itself has a say
+fn path_limit(context: &RuntimeContext, peer: &ConnectedPeer, destination: PublicKey) -> usize {
+    let limit = segmented_size_limit(peer.segment_size);
+
+    // Relays stream segments through without holding on to the whole message
+    if context.session_tracker.contains_key(&destination) {
+        limit.min(peer.max_message_size)
+    } else {
+        limit
+    }
+}
+
+/// Largest message we are willing to take delivery of, as told to peers during the handshake
+fn accepted_message_size(context: &RuntimeContext) -> usize {
+    context
+        .message_tracker
+        .max_message_size()
+        .min(context.config().max_decompressed_message_size)
+}
+
+/// The connected peer closest to the destination
+fn next_hop_peer(
+    context: &RuntimeContext,
+    destination: PublicKey,
+) -> Result<ConnectedPeer, RouteWeaverError> {
+    context
+        .routing_table
+        .next_hop(destination, &context.session_tracker)
+        .and_then(|next_hop| context.session_tracker.get(&next_hop))
+        .map(|peer| peer.clone())
+        .ok_or(RouteWeaverError::NoRoute)
+}
+
+/// Segment a message onto a session, taking `confirmation` only once the message is tracked
+async fn send_to_peer(
+    context: &RuntimeContext,
+    peer: &ConnectedPeer,
+    source: PublicKey,
+    destination: PublicKey,
+    message: &[u8],
+    confirmation: &mut Option<DeliveryConfirmation>,
+) -> Result<(), RouteWeaverError> {
+    let limit = path_limit(context, peer, destination);
+    if message.len() > limit {
+        return Err(RouteWeaverError::MessageTooLargeForPath {
+            size: message.len(),
+            limit,
+        });
+    }
+
+    let packets = segment_message(
+        source,
+        destination,
+        message,
+        peer.segment_size,
+        confirmation.is_some(),
+    )?;
+
+    // Tracked before anything goes out, the acknowledgement could otherwise beat us to it
+    if let Some(confirmation) = confirmation.take() {
+        context.deliveries.track(&packets, confirmation);
+    }
+
+    for packet in packets {
+        peer.packet_sender
+            .send(packet)
+            .await
+            .map_err(|_| RouteWeaverError::TransportConnection)?;
+    }
+
+    Ok(())
+}
+
+/// Compress a message of our own for a neighbor and queue it on its session directly
+async fn send_to_neighbor(
+    context: &RuntimeContext,
+    neighbor: PublicKey,
+    peer: &ConnectedPeer,
+    message: &Message,
+    confirmation: &mut Option<DeliveryConfirmation>,
+) -> Result<(), RouteWeaverError> {
+    let data = seal_message(context, neighbor, message, &peer.compression, None)?;
+
+    send_to_peer(
+        context,
+        peer,
+        context.config().public_key,
+        neighbor,
+        &data,
+        confirmation,
+    )
+    .await
+}
+
+/// Segment a sealed message and queue it on the session closest to its destination
+pub async fn send_encoded_message(
+    context: &RuntimeContext,
+    source: PublicKey,
+    destination: PublicKey,
+    message: &[u8],
+) -> Result<(), RouteWeaverError> {
+    let peer = next_hop_peer(context, destination)?;
+
+    send_to_peer(context, &peer, source, destination, message, &mut None).await
+}
+
+/// Dictionary to compress with, only usable when the next hop is also the one decompressing
+fn shared_dictionary<'a>(
+    context: &'a RuntimeContext,
+    peer: &ConnectedPeer,
+    destination: PublicKey,
+) -> Option<(DictionaryId, &'a [u8])> {
+    if context.session_tracker.contains_key(&destination) {
+        peer.dictionary
+            .and_then(|dictionary| context.dictionaries.get(&dictionary))
+    } else {
+        None
+    }
+}
+
+pub async fn encode_clear_text_message(context: RuntimeContext) {
     loop {
-        let message = message_queue.pop().await;
-        let message = encode_to_vec(&message.message, BINCODE_MESSAGE_CONFIG).unwrap();
+        let ClearTextMessage {
+            destination,
+            message,
+            mut confirmation,
+        } = tokio::select! {
+            message = context.message_queue.pop() => message,
+            _ = context.shutdown.cancelled() => return,
+        };
+
+        // Compression is picked for the link the message is about to take
+        let result = match next_hop_peer(&context, destination) {
+            Ok(peer) => match seal_message(
+                &context,
+                destination,
+                &message,
+                &peer.compression,
+                shared_dictionary(&context, &peer, destination),
+            ) {
+                Ok(data) => {
+                    send_to_peer(
+                        &context,
+                        &peer,
+                        context.config().public_key,
+                        destination,
+                        &data,
+                        &mut confirmation,
+                    )
+                    .await
+                }
+                Err(e) => Err(e),
+            },
+            Err(e) => Err(e),
+        };
 
-        let tracked_compression_mode = determine_compression_for_data(&message);
-        let mut tracked_index = 0;
+        if let Err(e) = result {
+            log::error!("Failed to send message to {}: {}", destination, e);
 
-        let message = Cursor::new(match tracked_compression_mode {
-            Some(MessageCompressionMode::Lz4) => lz4_flex::compress_prepend_size(&message),
-            Some(MessageCompressionMode::Zlib) => {
-                miniz_oxide::deflate::compress_to_vec_zlib(&message, 10)
+            // Still ours if it never got as far as being tracked, so the sender hears about it from us
+            if let Some(confirmation) = confirmation {
+                let _ = confirmation.send(Err(e));
             }
-            None => message,
-        });
+        }
     }
 }
 
+/// Shared state handed to every transport and connection task
+#[derive(Clone)]
+pub struct RuntimeContext {
+    pub config: SharedConfig,
+    pub message_queue: Arc<Queue<ClearTextMessage>>,
+    pub message_tracker: PreAssembledMessageTracker,
+    pub deliveries: PendingDeliveries,
+    pub abuse: Arc<AbuseTracker>,
+    pub session_tracker: SessionTracker,
+    pub routing_table: Arc<RoutingTable>,
+    pub dictionaries: Arc<ZstdDictionaries>,
+    pub encoded_message_sender: Sender<EncodedMessage>,
+    pub relayed_segment_sender: Sender<Packet>,
+    // Cancelled once we start shutting down, or just the transport these tasks belong to
+    pub shutdown: CancellationToken,
+    // Everything that has to wind down before we can exit
+    pub tasks: TaskTracker,
+}
+
+impl RuntimeContext {
+    /// The config currently in effect, which a reload may swap out at any time
+    pub fn config(&self) -> Arc<Config> {
+        self.config.borrow().clone()
+    }
+}
+
+/// Config shared by every task, with a way to get told when it is reloaded
+pub type SharedConfig = Arc<watch::Sender<Arc<Config>>>;
+
+const INITIAL_REDIAL_DELAY: Duration = Duration::from_secs(1);
+const MAX_REDIAL_DELAY: Duration = Duration::from_secs(300);
+
+struct RunningTransport {
+    // What it was started with, any change to it means starting it again
+    config: Option<TransportConfig>,
+    stop: CancellationToken,
+    handle: JoinHandle<()>,
+}
+
+/// Spawn a transport whose tasks all wind down once `stop` is cancelled
+fn spawn_transport(
+    context: &RuntimeContext,
+    protocol: Protocol,
+    stop: CancellationToken,
+) -> Option<JoinHandle<()>> {
+    let tasks = context.tasks.clone();
+    let context = RuntimeContext {
+        shutdown: stop,
+        ..context.clone()
+    };
+
+    match protocol {
+        #[cfg(tcp_transport)]
+        Protocol::Tcp => Some(
+            tasks.spawn(start_transport::<crate::transport::tcp::TcpTransport>(
+                context,
+            )),
+        ),
+        #[cfg(unix_transport)]
+        Protocol::Unix => Some(tasks.spawn(
+            start_transport::<crate::transport::unix::UnixTransport>(context),
+        )),
+        #[cfg(http_transport)]
+        Protocol::Http => Some(tasks.spawn(
+            start_transport::<crate::transport::http::HttpTransport>(context),
+        )),
+        #[cfg(irc_transport)]
+        Protocol::Irc => Some(
+            tasks.spawn(start_transport::<crate::transport::irc::IrcTransport>(
+                context,
+            )),
+        ),
+        #[allow(unreachable_patterns)]
+        _ => {
+            log::error!("Unsupported transport: {:?}", protocol);
+            None
+        }
+    }
+}
+
+/// Start and stop transports as the config enables, disables or reconfigures them
+pub async fn manage_transports(context: RuntimeContext) {
+    let mut config_updates = context.config.subscribe();
+    let mut running: HashMap<Protocol, RunningTransport> = HashMap::new();
+
+    loop {
+        let config = config_updates.borrow_and_update().clone();
+
+        // Reconfigured transports are stopped here and started again below
+        let stale = running
+            .iter()
+            .filter(|(protocol, transport)| {
+                !config.enabled_transports.contains(protocol)
+                    || config.transport_configs.get(protocol) != transport.config.as_ref()
+            })
+            .map(|(protocol, _)| *protocol)
+            .collect_vec();
+
+        for protocol in stale {
+            if let Some(transport) = running.remove(&protocol) {
+                log::info!("Stopping {} transport", protocol);
+                transport.stop.cancel();
+
+                // Returns only once the transport has shut down, so its address is free to bind again
+                let _ = transport.handle.await;
+            }
+        }
+
+        for protocol in &config.enabled_transports {
+            if running.contains_key(protocol) {
+                continue;
+            }
+
+            let stop = context.shutdown.child_token();
+            if let Some(handle) = spawn_transport(&context, *protocol, stop.clone()) {
+                running.insert(
+                    *protocol,
+                    RunningTransport {
+                        config: config.transport_configs.get(protocol).cloned(),
+                        stop,
+                        handle,
+                    },
+                );
+            }
+        }
+
+        tokio::select! {
+            changed = config_updates.changed() => {
+                if changed.is_err() {
+                    return;
+                }
+            }
+            _ = context.shutdown.cancelled() => return,
+        }
+    }
+}
+
+/// Bring up a transport, then accept connections on it and dial the seeders reachable through it
+pub async fn start_transport<T: Transport>(context: RuntimeContext) {
+    // Tracked on their own so the transport can outlive every connection made through it
+    let context = RuntimeContext {
+        tasks: TaskTracker::new(),
+        ..context
+    };
+    let config = context.config();
+    let transport_config = config.transport_configs.get(&T::PROTOCOL);
+
+    if let Err(e) = CompressionPolicy::from_transport_config(T::PROTOCOL, transport_config) {
+        log::error!("Failed to start {} transport: {}", T::PROTOCOL, e);
+        return;
+    }
+
+    let transport = match T::new(transport_config).await {
+        Ok(transport) => Arc::new(transport),
+        Err(e) => {
+            log::error!("Failed to start {} transport: {}", T::PROTOCOL, e);
+            return;
+        }
+    };
+
+    context
+        .tasks
+        .spawn(dial_seeders(transport.clone(), context.clone()));
+    accept_connections_from_peers(transport.clone(), context.clone()).await;
+
+    context.tasks.close();
+    if timeout(SHUTDOWN_GRACE_PERIOD, context.tasks.wait())
+        .await
+        .is_err()
+    {
+        log::warn!(
+            "{} connections on {} did not close in time",
+            context.tasks.len(),
+            T::PROTOCOL
+        );
+    }
+
+    transport.shutdown().await;
+    log::info!("Stopped {} transport", T::PROTOCOL);
+}
+
 pub async fn accept_connections_from_peers<T: Transport>(
-    message_queue: Arc<Queue<ClearTextMessage>>,
-    message_tracker: PreAssembledMessageTracker,
-    config: Arc<Config>,
+    transport: Arc<T>,
+    context: RuntimeContext,
 ) {
-    let transport_config = config.transport_configs.get(&T::PROTOCOL);
-    let transport = Arc::new(T::new(transport_config).await.unwrap());
+    loop {
+        let ((reader, writer), address) = tokio::select! {
+            accepted = transport.clone().accept() => match accepted {
+                Ok(accepted) => accepted,
+                Err(e) => {
+                    log::error!("Stopped accepting connections on {}: {}", T::PROTOCOL, e);
+                    break;
+                }
+            },
+            _ = context.shutdown.cancelled() => break,
+        };
 
-    while let Ok(((reader, _writer), address)) = transport.clone().accept().await {
-        if let Some(address) = address {
-            log::info!("Received connection from: {:?} on {}", address, T::PROTOCOL);
+        let peer = address.map(|address| Peer {
+            protocol: T::PROTOCOL,
+            address,
+        });
+
+        if let Some(peer) = &peer {
+            // Dropping the halves here closes the connection before anything is read from it
+            if context.config().is_denied_peer(peer) {
+                log::warn!("Refused connection from denied peer {}", peer);
+                continue;
+            }
+
+            log::info!(
+                "Received connection from: {:?} on {}",
+                peer.address,
+                T::PROTOCOL
+            );
         } else {
             log::info!("Received connection on {}", T::PROTOCOL);
         }
 
-        let (encoded_message_sender, encoded_message_receiver) = channel(1024);
-
-        tokio::spawn(encode_clear_text_message(
-            config.public_key,
-            message_queue.clone(),
-            encoded_message_sender.clone(),
-        ));
-
-        tokio::spawn(route_encoded_message(
+        context.tasks.spawn(handle_connection::<T>(
             transport.clone(),
-            encoded_message_receiver,
+            reader,
+            writer,
+            false,
+            peer,
+            context.clone(),
         ));
+    }
+}
+
+/// Keep dialing the seeders reachable through a transport, following the config as it is reloaded
+pub async fn dial_seeders<T: Transport>(transport: Arc<T>, context: RuntimeContext) {
+    let mut config_updates = context.config.subscribe();
+    let mut dialers: HashMap<Peer, CancellationToken> = HashMap::new();
+
+    loop {
+        let config = config_updates.borrow_and_update().clone();
+
+        // Established sessions are left alone, we just stop redialing them
+        dialers.retain(|seeder, stop| {
+            let keep = config.seeders.contains(seeder) && !config.is_denied_peer(seeder);
+
+            if !keep {
+                log::info!("No longer dialing {}", seeder);
+                stop.cancel();
+            }
+
+            keep
+        });
+
+        for seeder in &config.seeders {
+            if seeder.protocol != T::PROTOCOL || dialers.contains_key(seeder) {
+                continue;
+            }
+
+            if config.is_denied_peer(seeder) {
+                log::warn!("Not dialing denied seeder {}", seeder);
+                continue;
+            }
+
+            let stop = context.shutdown.child_token();
+            context.tasks.spawn(maintain_connection_to_peer(
+                transport.clone(),
+                seeder.address.clone(),
+                context.clone(),
+                stop.clone(),
+            ));
+            dialers.insert(seeder.clone(), stop);
+        }
+
+        tokio::select! {
+            changed = config_updates.changed() => {
+                if changed.is_err() {
+                    return;
+                }
+            }
+            _ = context.shutdown.cancelled() => return,
+        }
+    }
+}
+
+/// Keep a link to a peer alive, redialing with exponential backoff whenever it fails or drops
+pub async fn maintain_connection_to_peer<T: Transport>(
+    transport: Arc<T>,
+    address: Address,
+    context: RuntimeContext,
+    stop: CancellationToken,
+) {
+    let mut delay = INITIAL_REDIAL_DELAY;
+
+    loop {
+        let connected = tokio::select! {
+            connected = transport.clone().connect(Some(&address)) => connected,
+            _ = stop.cancelled() => return,
+        };
+
+        match connected {
+            Ok((reader, writer)) => {
+                log::info!("Connected to {:?} on {}", address, T::PROTOCOL);
+
+                let peer = Peer {
+                    protocol: T::PROTOCOL,
+                    address: address.clone(),
+                };
 
-        tokio::spawn(packet_listener::<T>(
+                match handle_connection::<T>(
+                    transport.clone(),
+                    reader,
+                    writer,
+                    true,
+                    Some(peer),
+                    context.clone(),
+                )
+                .await
+                {
+                    // Only a session that got established counts, a peer failing every handshake keeps backing off
+                    Ok(()) => delay = INITIAL_REDIAL_DELAY,
+                    Err(RouteWeaverError::Denied) => {
+                        log::warn!("Giving up on {:?} as its key is denied", address);
+                        return;
+                    }
+                    Err(_) => {}
+                }
+
+                if stop.is_cancelled() {
+                    return;
+                }
+
+                log::info!(
+                    "Lost connection to {:?} on {}, redialing in {:?}",
+                    address,
+                    T::PROTOCOL,
+                    delay
+                );
+            }
+            Err(e) => {
+                log::warn!(
+                    "Failed to connect to {:?} on {}: {}, retrying in {:?}",
+                    address,
+                    T::PROTOCOL,
+                    e,
+                    delay
+                );
+            }
+        }
+
+        tokio::select! {
+            _ = sleep(delay) => {}
+            _ = stop.cancelled() => return,
+        }
+        delay = (delay * 2).min(MAX_REDIAL_DELAY);
+    }
+}
+
+/// Run a connection from the handshake until it closes, failing unless a session got established
+pub async fn handle_connection<T: Transport>(
+    transport: Arc<T>,
+    mut reader: T::Reader,
+    mut writer: T::Writer,
+    initiator: bool,
+    peer: Option<Peer>,
+    context: RuntimeContext,
+) -> Result<(), RouteWeaverError> {
+    let (remote_key, session) = match perform_handshake(
+        &mut reader,
+        &mut writer,
+        &context.config().private_key,
+        initiator,
+        &context.dictionaries.ids(),
+        accepted_message_size(&context) as u64,
+    )
+    .await
+    {
+        Ok(session) => session,
+        Err(e) => {
+            log::error!("Handshake failed on {}: {}", T::PROTOCOL, e);
+            return Err(e);
+        }
+    };
+
+    // Already validated when the transport started
+    let compression = Arc::new(
+        CompressionPolicy::from_transport_config(
+            T::PROTOCOL,
+            context.config().transport_configs.get(&T::PROTOCOL),
+        )
+        .unwrap_or_else(|_| CompressionPolicy::default_for(T::PROTOCOL)),
+    );
+
+    if context.config().is_denied_key(&remote_key) {
+        log::warn!("Refused session with denied key {}", remote_key);
+
+        if let Err(e) = send_denied(&context, &mut writer, &session, remote_key, &compression).await
+        {
+            log::error!("Failed to notify {} of its denial: {}", remote_key, e);
+        }
+
+        return Err(RouteWeaverError::Denied);
+    }
+
+    log::info!("Established session with {} on {}", remote_key, T::PROTOCOL);
+
+    let session = Arc::new(session);
+    let (packet_sender, packet_receiver) = channel(1024);
+    let disconnect = context.shutdown.child_token();
+
+    context.session_tracker.insert(
+        remote_key,
+        ConnectedPeer {
+            packet_sender: packet_sender.clone(),
+            segment_size: transport
+                .recommended_message_segment_size()
+                .unwrap_or(MAX_MESSAGE_SEGMENT_SIZE),
+            compression,
+            dictionary: context
+                .dictionaries
+                .first_shared(&session.remote_dictionaries),
+            max_message_size: usize::try_from(session.remote_max_message_size)
+                .unwrap_or(usize::MAX),
+            peer,
+            disconnect: disconnect.clone(),
+        },
+    );
+
+    // Outlives the listener so whatever is still queued gets flushed
+    context
+        .tasks
+        .spawn(packet_writer(writer, packet_receiver, session.clone()));
+
+    // Tell our new neighbor what it can reach through us
+    context.message_queue.push(ClearTextMessage {
+        destination: remote_key,
+        message: create_peers_list(&context, remote_key),
+        confirmation: None,
+    });
+
+    tokio::select! {
+        _ = packet_listener(
             reader,
-            encoded_message_sender.clone(),
-            message_tracker.clone(),
-        ));
+            session,
+            context.encoded_message_sender.clone(),
+            context.message_tracker.clone(),
+            Some(Relay {
+                local_key: context.config().public_key,
+                private_key: context.config().private_key.clone(),
+                sender: context.relayed_segment_sender.clone(),
+                deliveries: context.deliveries.clone(),
+                neighbors: context.session_tracker.clone(),
+                abuse: context.abuse.clone(),
+            }),
+        ) => {}
+        _ = disconnect.cancelled() => {}
+    }
+
+    // A newer connection may have already replaced us
+    if context
+        .session_tracker
+        .remove_if(&remote_key, |_, peer| {
+            peer.packet_sender.same_channel(&packet_sender)
+        })
+        .is_some()
+    {
+        context.routing_table.remove_next_hop(remote_key);
+    }
+
+    log::info!("Session with {} on {} closed", remote_key, T::PROTOCOL);
+
+    Ok(())
+}
+
+/// Tell a peer it's denied over its session before hanging up on it
+async fn send_denied(
+    context: &RuntimeContext,
+    writer: &mut impl TransportWriter,
+    session: &Session,
+    remote_key: PublicKey,
+    compression: &CompressionPolicy,
+) -> Result<(), RouteWeaverError> {
+    let data = seal_message(context, remote_key, &Message::Denied, compression, None)?;
+
+    for mut packet in segment_message(
+        context.config().public_key,
+        remote_key,
+        &data,
+        MAX_MESSAGE_SEGMENT_SIZE,
+        false,
+    )? {
+        packet.message = session.encrypt_segment(&packet.message)?;
+        writer.feed(packet).await?;
     }
+
+    writer.close().await
 }
 
-pub type PreAssembledMessageTracker =
-    Arc<HashCache<(PublicKey, PublicKey), DashMap<u8, LimitedVec<u8, MAX_MESSAGE_SEGMENT_SIZE>>>>;
+pub async fn packet_writer(
+    writer: impl TransportWriter,
+    mut packet_receiver: Receiver<Packet>,
+    session: Arc<Session>,
+) {
+    let mut writer = Box::pin(writer);
+
+    while let Some(mut packet) = packet_receiver.recv().await {
+        packet.message = match session.encrypt_segment(&packet.message) {
+            Ok(segment) => segment,
+            Err(e) => {
+                log::error!("Error encrypting packet: {}", e);
+                continue;
+            }
+        };
+
+        if let Err(e) = writer.send(packet).await {
+            log::error!("Error writing packet: {}", e);
+            break;
+        }
+    }
+
+    // Every sender is gone, so flush what the transport still buffers and hang up
+    if let Err(e) = writer.close().await {
+        log::warn!("Error closing connection: {}", e);
+    }
+}
+
+pub type SessionTracker = Arc<DashMap<PublicKey, ConnectedPeer>>;
+
+pub type PreAssembledMessageTracker = Arc<MessageTracker>;
+
+pub type PendingDeliveries = Arc<DeliveryTracker>;
+
+const REASSEMBLY_EXPIRY_INTERVAL: Duration = Duration::from_secs(10);
+
+/// Periodically throw away messages whose remaining segments never showed up
+pub async fn expire_pre_assembled_messages(context: RuntimeContext) {
+    loop {
+        tokio::select! {
+            _ = sleep(REASSEMBLY_EXPIRY_INTERVAL) => {}
+            _ = context.shutdown.cancelled() => return,
+        }
+
+        context.message_tracker.expire();
+
+        log::debug!(
+            "{} bytes waiting on reassembly, {} messages abandoned and {} offenses dropped so far",
+            context.message_tracker.pending_bytes(),
+            context.message_tracker.evictions(),
+            context.abuse.total()
+        );
+    }
+}
 
 #[derive(Debug)]
 pub struct EncodedMessage {
+    // Only vouched for once the seal is opened
     pub claimed_source: PublicKey,
     pub claimed_destination: PublicKey,
-    pub compression_mode: Option<MessageCompressionMode>,
+    // The neighbor that handed it to us, as authenticated by its session
+    pub received_from: PublicKey,
+    // Sealed, so only the destination can make anything of it
     pub message: Vec<u8>,
 }
 
-pub async fn route_encoded_message<T: Transport>(
-    transport: Arc<T>,
+const ROUTE_ADVERTISEMENT_INTERVAL: Duration = Duration::from_secs(30);
+
+pub fn create_peers_list(context: &RuntimeContext, neighbor: PublicKey) -> Message {
+    Message::PeersList {
+        peers: context.config().seeders.clone(),
+        routes: context
+            .routing_table
+            .advertisement_for(neighbor, &context.session_tracker),
+    }
+}
+
+/// Periodically refresh every neighbor's view of what it can reach through us
+pub async fn advertise_routes(context: RuntimeContext) {
+    loop {
+        tokio::select! {
+            _ = sleep(ROUTE_ADVERTISEMENT_INTERVAL) => {}
+            _ = context.shutdown.cancelled() => return,
+        }
+
+        let neighbors = context
+            .session_tracker
+            .iter()
+            .map(|peer| *peer.key())
+            .collect_vec();
+
+        for neighbor in neighbors {
+            context.message_queue.push(ClearTextMessage {
+                destination: neighbor,
+                message: create_peers_list(&context, neighbor),
+                confirmation: None,
+            });
+        }
+    }
+}
+
+// How long writers get to flush once everything else has been told to stop
+const SHUTDOWN_GRACE_PERIOD: Duration = Duration::from_secs(5);
+
+/// Let every neighbor know we are about to hang up on it, waiting until each has heard us
+pub async fn say_goodbye(context: &RuntimeContext) {
+    let neighbors = context
+        .session_tracker
+        .iter()
+        .map(|peer| (*peer.key(), peer.clone()))
+        .collect_vec();
+    let mut confirmations = Vec::new();
+
+    for (neighbor, peer) in neighbors {
+        let (confirmation, confirmed) = oneshot::channel();
+
+        match send_to_neighbor(
+            context,
+            neighbor,
+            &peer,
+            &Message::Goodbye,
+            &mut Some(confirmation),
+        )
+        .await
+        {
+            Ok(()) => confirmations.push((neighbor, confirmed)),
+            Err(e) => log::warn!("Failed to say goodbye to {}: {}", neighbor, e),
+        }
+    }
+
+    for (neighbor, confirmed) in confirmations {
+        if let Ok(Err(e)) = confirmed.await {
+            log::warn!("Failed to say goodbye to {}: {}", neighbor, e);
+        }
+    }
+}
+
+/// Hang up on every neighbor the deny list has come to cover, telling it why first
+pub async fn drop_denied_peers(context: &RuntimeContext) {
+    let config = context.config();
+    let denied = context
+        .session_tracker
+        .iter()
+        .filter(|peer| {
+            config.is_denied_key(peer.key())
+                || peer
+                    .peer
+                    .as_ref()
+                    .is_some_and(|address| config.is_denied_peer(address))
+        })
+        .map(|peer| (*peer.key(), peer.clone()))
+        .collect_vec();
+
+    for (neighbor, peer) in denied {
+        log::warn!("Dropping session with {} as it is now denied", neighbor);
+
+        if let Err(e) =
+            send_to_neighbor(context, neighbor, &peer, &Message::Denied, &mut None).await
+        {
+            log::error!("Failed to notify {} of its denial: {}", neighbor, e);
+        }
+
+        // The writer still flushes the denial before hanging up
+        peer.disconnect.cancel();
+    }
+}
+
+/// Re-read the config file and apply whatever changed, keeping the running config if it is no good
+pub async fn reload_config(context: &RuntimeContext, path: &Path) -> Result<(), RouteWeaverError> {
+    let config = Config::load(path)?;
+    let current = context.config();
+
+    // Every session we hold was authenticated with the old identity
+    if config.public_key != current.public_key {
+        return Err(RouteWeaverError::InvalidConfig(
+            "changing the identity needs a restart".to_string(),
+        ));
+    }
+
+    if config.reassembly != current.reassembly {
+        log::warn!("New reassembly limits only apply after a restart");
+    }
+
+    if config.zstd_dictionaries != current.zstd_dictionaries {
+        log::warn!("New zstd dictionaries only apply after a restart");
+    }
+
+    if config.admin_socket != current.admin_socket {
+        log::warn!("A new admin socket only applies after a restart");
+    }
+
+    // Transports and seeder dialers pick the change up on their own
+    context.config.send_replace(Arc::new(config));
+    drop_denied_peers(context).await;
+
+    log::info!("Reloaded config from {}", path.display());
+
+    Ok(())
+}
+
+/// Say goodbye, stop every task and wait for the writers to flush what they still hold
+pub async fn shutdown(context: &RuntimeContext) {
+    if timeout(SHUTDOWN_GRACE_PERIOD, say_goodbye(context))
+        .await
+        .is_err()
+    {
+        log::warn!("Gave up saying goodbye to our neighbors");
+    }
+
+    context.shutdown.cancel();
+    context.tasks.close();
+
+    if timeout(SHUTDOWN_GRACE_PERIOD, context.tasks.wait())
+        .await
+        .is_err()
+    {
+        log::warn!(
+            "{} tasks did not stop within {:?}",
+            context.tasks.len(),
+            SHUTDOWN_GRACE_PERIOD
+        );
+    }
+
+    let pending_messages = context.message_tracker.pending_messages();
+    if pending_messages > 0 {
+        log::warn!(
+            "Dropping {} partially received messages ({} bytes)",
+            pending_messages,
+            context.message_tracker.pending_bytes()
+        );
+    }
+
+    let pending_deliveries = context.deliveries.pending_messages();
+    if pending_deliveries > 0 {
+        log::warn!(
+            "Giving up on {} messages still waiting to be acknowledged",
+            pending_deliveries
+        );
+    }
+}
+
+/// Undo whatever compression the sender applied, refusing to inflate past `max_size`
+pub fn decompress_message(
+    compression_mode: Option<MessageCompressionMode>,
+    message: Vec<u8>,
+    max_size: usize,
+    dictionaries: &ZstdDictionaries,
+) -> Result<Vec<u8>, RouteWeaverError> {
+    let decompressed = match compression_mode {
+        Some(MessageCompressionMode::Lz4) => {
+            // The size is only a claim, but it is what lz4 allocates up front
+            let (size, _) = lz4_flex::block::uncompressed_size(&message)
+                .map_err(|_| RouteWeaverError::Decompression)?;
+
+            if size > max_size {
+                return Err(RouteWeaverError::MessageTooLarge);
+            }
+
+            lz4_flex::decompress_size_prepended(&message)
+                .map_err(|_| RouteWeaverError::Decompression)?
+        }
+        Some(MessageCompressionMode::Zlib) => {
+            miniz_oxide::inflate::decompress_to_vec_zlib_with_limit(&message, max_size).map_err(
+                |e| match e.status {
+                    TINFLStatus::HasMoreOutput => RouteWeaverError::MessageTooLarge,
+                    _ => RouteWeaverError::Decompression,
+                },
+            )?
+        }
+        Some(MessageCompressionMode::Zstd { dictionary }) => {
+            dictionaries.decompress(dictionary, &message, max_size)?
+        }
+        None => message,
+    };
+
+    if decompressed.len() > max_size {
+        return Err(RouteWeaverError::MessageTooLarge);
+    }
+
+    Ok(decompressed)
+}
+
+/// Open a message sealed for `key` and undo everything its source did to it
+pub fn decode_message(
+    message: &EncodedMessage,
+    key: &PrivateKey,
+    max_size: usize,
+    dictionaries: &ZstdDictionaries,
+) -> Result<Message, RouteWeaverError> {
+    let (compression_mode, data) = open(key, &message.claimed_source, &message.message)?;
+    // Already no larger than max_size, which the config keeps within what the decode config allows
+    let data = decompress_message(compression_mode, data, max_size, dictionaries)?;
+
+    Ok(decode_from_slice(&data, BINCODE_DECOMPRESSED_MESSAGE_CONFIG)?.0)
+}
+
+fn reply(context: &RuntimeContext, destination: PublicKey, message: Message) {
+    context.message_queue.push(ClearTextMessage {
+        destination,
+        message,
+        confirmation: None,
+    });
+}
+
+pub fn handle_message(context: &RuntimeContext, source: PublicKey, message: Message) {
+    match message {
+        Message::Denied => {
+            log::warn!("{} has denied us", source);
+        }
+        Message::Handshake { .. } => {
+            // Only meaningful as the payload of a Noise handshake
+            log::warn!("Ignoring stray handshake message from {}", source);
+        }
+        Message::RequestPeersList => {
+            reply(context, source, create_peers_list(context, source));
+        }
+        Message::PeersList { peers, routes } => {
+            // Only our direct neighbors can be used as a next hop
+            if context.session_tracker.contains_key(&source) {
+                context.routing_table.update_routes_via(
+                    context.config().public_key,
+                    source,
+                    &routes,
+                );
+            } else {
+                log::warn!("Ignoring routes from {} as it is not a neighbor", source);
+            }
+
+            log::debug!("{} knows of {} peers", source, peers.len());
+        }
+        Message::RequestSystemInformation => {
+            reply(
+                context,
+                source,
+                Message::SystemInformation {
+                    // We don't run compute modules for anyone yet
+                    compute_max_time: None,
+                },
+            );
+        }
+        Message::SystemInformation { compute_max_time } => {
+            log::info!(
+                "{} allows compute modules to run for {:?}",
+                source,
+                compute_max_time
+            );
+        }
+        Message::RequestApplicationAdvertisement => {
+            reply(
+                context,
+                source,
+                Message::ApplicationAdvertisement {
+                    applications: HashSet::new(),
+                },
+            );
+        }
+        Message::ApplicationAdvertisement { applications } => {
+            log::info!("{} advertises applications {:?}", source, applications);
+        }
+        Message::Goodbye => {
+            log::info!("{} is shutting down", source);
+        }
+    }
+}
+
+/// Deliver messages meant for us and forward everything else towards its destination
+pub async fn route_encoded_message(
+    context: RuntimeContext,
     mut encoded_message_receiver: Receiver<EncodedMessage>,
 ) {
-    while let Some(complete_message) = encoded_message_receiver.recv().await {
-        log::info!(
-            "received complete message from {:?} to {:?}",
+    loop {
+        let complete_message = tokio::select! {
+            Some(complete_message) = encoded_message_receiver.recv() => complete_message,
+            _ = context.shutdown.cancelled() => return,
+            else => return,
+        };
+
+        if complete_message.claimed_destination == context.config().public_key {
+            match decode_message(
+                &complete_message,
+                &context.config().private_key,
+                context.config().max_decompressed_message_size,
+                &context.dictionaries,
+            ) {
+                Ok(message) => handle_message(&context, complete_message.claimed_source, message),
+                // Relays can't open the seal, so only a neighbor sending as itself is to blame
+                Err(RouteWeaverError::ForgedSource(source))
+                    if complete_message.received_from == source =>
+                {
+                    context.abuse.record(source, Offense::ForgedSource)
+                }
+                Err(e) => log::error!(
+                    "Failed to decode message from {}: {}",
+                    complete_message.claimed_source,
+                    e
+                ),
+            }
+
+            continue;
+        }
+
+        if let Err(e) = send_encoded_message(
+            &context,
             complete_message.claimed_source,
-            complete_message.claimed_destination
-        );
+            complete_message.claimed_destination,
+            &complete_message.message,
+        )
+        .await
+        {
+            log::warn!(
+                "Failed to forward message from {} to {}: {}",
+                complete_message.claimed_source,
+                complete_message.claimed_destination,
+                e
+            );
+        }
     }
 }
 
-pub async fn packet_listener<T: Transport>(
-    reader: T::Reader,
+/// Where a listener passes on segments of messages meant for someone else, and its own replies
+#[derive(Clone)]
+pub struct Relay {
+    // Only messages addressed to this key get reassembled
+    pub local_key: PublicKey,
+    // Tags our replies and checks the ones we get
+    pub private_key: Arc<PrivateKey>,
+    pub sender: Sender<Packet>,
+    // Reliable messages of ours, which acknowledgements and requests for segments refer to
+    pub deliveries: PendingDeliveries,
+    // Whoever is in here talks to us directly, never by way of someone else
+    pub neighbors: SessionTracker,
+    pub abuse: Arc<AbuseTracker>,
+}
+
+/// Route a packet back towards the sender of a message, false once the relay has gone away
+async fn reply_to_sender(
+    relay: Option<&Relay>,
+    sender: PublicKey,
+    message: MessageSegment,
+) -> bool {
+    let Some(relay) = relay else {
+        log::debug!("Unable to answer {} without a relay", sender);
+        return true;
+    };
+
+    let message = match tag_reply(&relay.private_key, &relay.local_key, &sender, message) {
+        Ok(message) => message,
+        Err(e) => {
+            log::error!("Failed to tag reply to {}: {}", sender, e);
+            return true;
+        }
+    };
+
+    relay
+        .sender
+        .send(Packet {
+            source: relay.local_key,
+            destination: sender,
+            message,
+        })
+        .await
+        .is_ok()
+}
+
+const RETRANSMIT_CHECK_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Resend the end of every reliable message that went unanswered, so its destination speaks up
+pub async fn retransmit_unacknowledged(context: RuntimeContext) {
+    loop {
+        tokio::select! {
+            _ = sleep(RETRANSMIT_CHECK_INTERVAL) => {}
+            _ = context.shutdown.cancelled() => return,
+        }
+
+        for packet in context.deliveries.due() {
+            // Ends go the same way relayed segments do, whatever link is best by now
+            if context.relayed_segment_sender.send(packet).await.is_err() {
+                return;
+            }
+        }
+    }
+}
+
+/// Pass segments through to the next hop as they arrive, so relays never buffer whole messages
+pub async fn relay_segments(
+    context: RuntimeContext,
+    mut relayed_segment_receiver: Receiver<Packet>,
+) {
+    loop {
+        let packet = tokio::select! {
+            Some(packet) = relayed_segment_receiver.recv() => packet,
+            _ = context.shutdown.cancelled() => return,
+            else => return,
+        };
+
+        let (source, destination) = (packet.source, packet.destination);
+        let end = matches!(packet.message, MessageSegment::EndMessage { .. });
+
+        let result = match next_hop_peer(&context, destination) {
+            Ok(peer) => peer
+                .packet_sender
+                .send(packet)
+                .await
+                .map_err(|_| RouteWeaverError::TransportConnection),
+            Err(e) => Err(e),
+        };
+
+        // Once per message is plenty, the end is what decides whether it arrives anyway
+        if let Err(e) = result {
+            if end {
+                log::warn!(
+                    "Failed to forward message from {} to {}: {}",
+                    source,
+                    destination,
+                    e
+                );
+            } else {
+                log::debug!(
+                    "Failed to forward segment from {} to {}: {}",
+                    source,
+                    destination,
+                    e
+                );
+            }
+        }
+    }
+}
+
+pub async fn packet_listener(
+    reader: impl TransportReader,
+    session: Arc<Session>,
     complete_message_sender: Sender<EncodedMessage>,
     pre_assembled_message_tracker: PreAssembledMessageTracker,
+    relay: Option<Relay>,
 ) {
     let mut reader = Box::pin(reader);
 
     while let Some(packet) = reader.next().await {
         match packet {
             Ok(packet) => {
-                // Match the message segment type
-                match packet.message {
-                    // It's the actual data for the message
-                    MessageSegment::Message { index, data } => {
-                        let (_, message) = pre_assembled_message_tracker
-                            .entry((packet.source, packet.destination))
-                            .or_default();
-                        let message = message.get();
+                let segment = match session.decrypt_segment(packet.message) {
+                    Ok(segment) => segment,
+                    Err(e) => {
+                        log::error!("Error decrypting packet from {}: {}", packet.source, e);
+                        continue;
+                    }
+                };
 
-                        if message.contains_key(&index) {
+                // The session only vouches for the neighbor's own traffic, anything else is relayed by it,
+                // which no neighbor has reason to do for us or another neighbor, whoever it is headed to
+                if packet.source != session.remote_key {
+                    match &relay {
+                        // Traffic still in flight when a neighbor connects can trip this, which reliable senders recover from
+                        Some(relay)
+                            if packet.source == relay.local_key
+                                || relay.neighbors.contains_key(&packet.source) =>
+                        {
+                            relay
+                                .abuse
+                                .record(session.remote_key, Offense::Impersonation);
+                            continue;
+                        }
+                        Some(_) => {}
+                        None => {
                             log::warn!(
-                                "Received duplicate message segment from: {}",
-                                packet.source
+                                "Dropping packet from {} relayed by {}, as we take no relayed traffic",
+                                packet.source,
+                                session.remote_key
                             );
+                            continue;
                         }
+                    }
+                }
+
+                if let Some(relay) = relay
+                    .as_ref()
+                    .filter(|relay| packet.destination != relay.local_key)
+                {
+                    let segment = Packet {
+                        source: packet.source,
+                        destination: packet.destination,
+                        message: segment,
+                    };
+
+                    // The relay only goes away when we are shutting down
+                    if relay.sender.send(segment).await.is_err() {
+                        return;
+                    }
+
+                    continue;
+                }
+
+                // Replies steer our retransmissions, so they have to come from the end they claim to
+                if let MessageSegment::Acknowledge { .. } | MessageSegment::MissingSegments { .. } =
+                    &segment
+                {
+                    let Some(relay) = &relay else {
+                        continue;
+                    };
 
-                        message.insert(index, data);
+                    if verify_reply(&relay.private_key, &packet.source, &segment).is_err() {
+                        relay.abuse.record(session.remote_key, Offense::ForgedReply);
+                        continue;
+                    }
+                }
+
+                // Match the message segment type
+                match segment {
+                    // It's the actual data for the message
+                    MessageSegment::Message { id, index, data } => {
+                        pre_assembled_message_tracker.insert_segment(
+                            (packet.source, packet.destination, id),
+                            session.remote_key,
+                            index,
+                            data,
+                        );
                     }
                     MessageSegment::EndMessage {
+                        id,
                         total_indexes,
                         hash,
-                        compression_mode,
+                        reliable,
                     } => {
-                        if let Some((_, message)) = pre_assembled_message_tracker
-                            .remove(&(packet.source, packet.destination))
-                        {
-                            let stored_length = message.len();
-                            if stored_length != total_indexes.get() as usize {
-                                log::error!(
-                                    "Mismatch in message segment count, expected: {}, actual: {}",
-                                    total_indexes,
-                                    message.len()
-                                );
-                                continue;
-                            }
+                        let key = (packet.source, packet.destination, id);
 
-                            let sorted_message =
-                                message.into_iter().sorted_by_key(|(x, _)| *x).collect_vec();
+                        if reliable {
+                            // Our acknowledgement got lost, so the sender is asking again
+                            if pre_assembled_message_tracker.was_delivered(&key) {
+                                if !reply_to_sender(
+                                    relay.as_ref(),
+                                    packet.source,
+                                    MessageSegment::Acknowledge { id, tag: [0; 32] },
+                                )
+                                .await
+                                {
+                                    return;
+                                }
 
-                            let mut hasher = Blake2s256::default();
-                            for (_, segment) in &sorted_message {
-                                hasher.update(&segment.0);
-                            }
-                            if hasher.finalize().as_slice() != hash {
-                                log::error!("Message hash does not match");
                                 continue;
                             }
 
-                            let mut final_buffer = Vec::new();
-                            for (_, segment) in sorted_message {
-                                final_buffer.extend_from_slice(&segment.0);
-                            }
+                            let missing = pre_assembled_message_tracker
+                                .missing_segments(&key, total_indexes.get());
+
+                            if !missing.is_empty() {
+                                log::debug!(
+                                    "Asking {} for {} missing segments of message {}",
+                                    packet.source,
+                                    missing.len(),
+                                    id
+                                );
 
-                            complete_message_sender
-                                .send(EncodedMessage {
-                                    claimed_source: packet.source,
-                                    claimed_destination: packet.destination,
-                                    compression_mode,
-                                    message: final_buffer,
-                                })
+                                if !reply_to_sender(
+                                    relay.as_ref(),
+                                    packet.source,
+                                    MessageSegment::MissingSegments {
+                                        id,
+                                        indexes: LimitedVec(missing),
+                                        tag: [0; 32],
+                                    },
+                                )
                                 .await
-                                .unwrap();
+                                {
+                                    return;
+                                }
 
-                            log::info!(
-                                "Complete message sent successfully from {} to {}",
-                                packet.source,
-                                packet.destination
-                            );
-                        } else {
+                                continue;
+                            }
+                        }
+
+                        let Some(message) = pre_assembled_message_tracker.take(&key) else {
                             log::error!(
                                 "Received end message without start message from: {}",
                                 packet.source
                             );
+                            continue;
+                        };
+
+                        let intact = if message.segments != total_indexes.get()
+                            || message.stray_segments != 0
+                        {
+                            log::error!(
+                                "Mismatch in message segment count, expected: {}, actual: {} ({} past a gap)",
+                                total_indexes,
+                                message.segments,
+                                message.stray_segments
+                            );
+                            false
+                        } else if message.hash != hash {
+                            log::error!("Message hash does not match");
+                            false
+                        } else {
+                            true
+                        };
+
+                        if !intact {
+                            // Nothing of it is left with us, so a reliable sender starts over
+                            if reliable
+                                && !reply_to_sender(
+                                    relay.as_ref(),
+                                    packet.source,
+                                    MessageSegment::MissingSegments {
+                                        id,
+                                        indexes: LimitedVec(
+                                            pre_assembled_message_tracker
+                                                .missing_segments(&key, total_indexes.get()),
+                                        ),
+                                        tag: [0; 32],
+                                    },
+                                )
+                                .await
+                            {
+                                return;
+                            }
+
+                            continue;
+                        }
+
+                        // The router only goes away when we are shutting down
+                        if complete_message_sender
+                            .send(EncodedMessage {
+                                claimed_source: packet.source,
+                                claimed_destination: packet.destination,
+                                received_from: session.remote_key,
+                                message: message.data,
+                            })
+                            .await
+                            .is_err()
+                        {
+                            return;
+                        }
+
+                        log::info!(
+                            "Complete message sent successfully from {} to {}",
+                            packet.source,
+                            packet.destination
+                        );
+
+                        if reliable {
+                            pre_assembled_message_tracker.mark_delivered(key);
+
+                            if !reply_to_sender(
+                                relay.as_ref(),
+                                packet.source,
+                                MessageSegment::Acknowledge { id, tag: [0; 32] },
+                            )
+                            .await
+                            {
+                                return;
+                            }
+                        }
+                    }
+                    MessageSegment::Acknowledge { id, .. } => {
+                        if let Some(relay) = &relay {
+                            relay.deliveries.acknowledge(&(packet.source, id));
                         }
                     }
+                    MessageSegment::MissingSegments { id, indexes, .. } => {
+                        let Some(relay) = &relay else {
+                            continue;
+                        };
+
+                        for resent in relay
+                            .deliveries
+                            .retransmit(&(packet.source, id), &indexes.0)
+                        {
+                            if relay.sender.send(resent).await.is_err() {
+                                return;
+                            }
+                        }
+                    }
+                    // Session frames never survive decryption
+                    MessageSegment::Handshake { .. } | MessageSegment::Encrypted { .. } => {
+                        unreachable!()
+                    }
                 }
             }
             Err(e) => {
@@ -0,0 +1,199 @@
+use crate::{
+    error::RouteWeaverError,
+    peer::NOISE_PROLOGUE,
+    proto::{
+        MessageCompressionMode, MessageSegment, PrivateKey, PublicKey, SealedHeader,
+        BINCODE_MESSAGE_CONFIG, MAX_NOISE_MESSAGE_SIZE,
+    },
+};
+use bincode::serde::{decode_from_slice, encode_to_vec};
+use blake2::{digest::Mac, Blake2sMac256};
+use once_cell::sync::Lazy;
+use snow::{
+    params::NoiseParams,
+    resolvers::{CryptoResolver, DefaultResolver},
+};
+use zeroize::Zeroizing;
+
+// One way, so the destination never has to be online for us to seal something for it
+static SEAL_PATTERN: Lazy<NoiseParams> =
+    Lazy::new(|| "Noise_X_25519_ChaChaPoly_BLAKE2s".parse().unwrap());
+
+// Noise authenticates everything it encrypts with a tag this long
+const TAG_SIZE: usize = 16;
+// Plaintext carried by each transport message following the handshake
+const SEALED_CHUNK_SIZE: usize = MAX_NOISE_MESSAGE_SIZE - TAG_SIZE;
+
+fn create_seal_builder<'a>() -> snow::Builder<'a> {
+    snow::Builder::new(SEAL_PATTERN.clone()).prologue(NOISE_PROLOGUE.as_bytes())
+}
+
+/// Encrypt a message so only `destination` can read it, and can tell it came from us
+///
+/// The handshake message carries the header, the data follows it in as many transport messages as it takes
+pub fn seal(
+    key: &PrivateKey,
+    destination: &PublicKey,
+    compression_mode: Option<MessageCompressionMode>,
+    data: &[u8],
+) -> Result<Vec<u8>, RouteWeaverError> {
+    let mut noise = create_seal_builder()
+        .local_private_key(&key.0)
+        .remote_public_key(&destination.0)
+        .build_initiator()?;
+
+    let header = encode_to_vec(
+        SealedHeader {
+            compression_mode,
+            length: data.len() as u64,
+        },
+        BINCODE_MESSAGE_CONFIG,
+    )
+    .map_err(|_| RouteWeaverError::PacketEncoding)?;
+
+    let mut handshake = vec![0; MAX_NOISE_MESSAGE_SIZE];
+    let length = noise.write_message(&header, &mut handshake)?;
+
+    let mut sealed = Vec::with_capacity(
+        2 + length + data.len() + data.len().div_ceil(SEALED_CHUNK_SIZE) * TAG_SIZE,
+    );
+    sealed.extend_from_slice(&(length as u16).to_be_bytes());
+    sealed.extend_from_slice(&handshake[..length]);
+
+    let mut noise = noise.into_transport_mode()?;
+    for chunk in data.chunks(SEALED_CHUNK_SIZE) {
+        // Encrypted straight into place
+        let start = sealed.len();
+        sealed.resize(start + chunk.len() + TAG_SIZE, 0);
+        noise.write_message(chunk, &mut sealed[start..])?;
+    }
+
+    Ok(sealed)
+}
+
+/// Decrypt a message sealed for us, refusing it unless `source` is the one who sealed it
+pub fn open(
+    key: &PrivateKey,
+    source: &PublicKey,
+    sealed: &[u8],
+) -> Result<(Option<MessageCompressionMode>, Vec<u8>), RouteWeaverError> {
+    let (length, rest) = sealed
+        .split_first_chunk::<2>()
+        .ok_or(RouteWeaverError::MalformedSeal)?;
+    let length = u16::from_be_bytes(*length) as usize;
+
+    if rest.len() < length {
+        return Err(RouteWeaverError::MalformedSeal);
+    }
+    let (handshake, chunks) = rest.split_at(length);
+
+    let mut noise = create_seal_builder()
+        .local_private_key(&key.0)
+        .build_responder()?;
+
+    let mut header = vec![0; MAX_NOISE_MESSAGE_SIZE];
+    let header_length = noise.read_message(handshake, &mut header)?;
+
+    if noise.get_remote_static() != Some(source.0.as_slice()) {
+        return Err(RouteWeaverError::ForgedSource(*source));
+    }
+
+    let (header, _): (SealedHeader, _) =
+        decode_from_slice(&header[..header_length], BINCODE_MESSAGE_CONFIG)?;
+
+    // Checked before allocating, so the header can't make us reserve more than we were sent
+    let length = usize::try_from(header.length).map_err(|_| RouteWeaverError::MalformedSeal)?;
+    if length.checked_add(length.div_ceil(SEALED_CHUNK_SIZE) * TAG_SIZE) != Some(chunks.len()) {
+        return Err(RouteWeaverError::MalformedSeal);
+    }
+
+    let mut data = vec![0; length];
+    let mut noise = noise.into_transport_mode()?;
+    for (chunk, plaintext) in chunks
+        .chunks(MAX_NOISE_MESSAGE_SIZE)
+        .zip(data.chunks_mut(SEALED_CHUNK_SIZE))
+    {
+        noise.read_message(chunk, plaintext)?;
+    }
+
+    Ok((header.compression_mode, data))
+}
+
+/// MAC over a reply about a message between us and `remote`, keyed by what only the two of us can compute
+fn reply_mac(
+    key: &PrivateKey,
+    remote: &PublicKey,
+    replier: &PublicKey,
+    reply: &MessageSegment,
+) -> Result<Blake2sMac256, RouteWeaverError> {
+    let mut dh = DefaultResolver.resolve_dh(&SEAL_PATTERN.dh).unwrap();
+    dh.set(&key.0);
+
+    let mut shared = Zeroizing::new([0; 32]);
+    dh.dh(&remote.0, shared.as_mut_slice())?;
+
+    let mut mac = Blake2sMac256::new_with_salt_and_personal(shared.as_slice(), &[], b"rw-reply")
+        .map_err(|_| RouteWeaverError::PacketEncoding)?;
+
+    // Naming who replies keeps a reply from being turned around at its sender
+    mac.update(NOISE_PROLOGUE.as_bytes());
+    mac.update(&replier.0);
+
+    match reply {
+        MessageSegment::Acknowledge { id, .. } => {
+            mac.update(b"acknowledge");
+            mac.update(&id.0.to_be_bytes());
+        }
+        MessageSegment::MissingSegments { id, indexes, .. } => {
+            mac.update(b"missing");
+            mac.update(&id.0.to_be_bytes());
+
+            for index in &indexes.0 {
+                mac.update(&index.to_be_bytes());
+            }
+        }
+        _ => return Err(RouteWeaverError::UnexpectedSegment),
+    }
+
+    Ok(mac)
+}
+
+/// Fill in the tag of a reply we send to `remote` about one of its messages
+pub fn tag_reply(
+    key: &PrivateKey,
+    local_key: &PublicKey,
+    remote: &PublicKey,
+    mut reply: MessageSegment,
+) -> Result<MessageSegment, RouteWeaverError> {
+    let computed = reply_mac(key, remote, local_key, &reply)?
+        .finalize()
+        .into_bytes()
+        .into();
+
+    match &mut reply {
+        MessageSegment::Acknowledge { tag, .. } | MessageSegment::MissingSegments { tag, .. } => {
+            *tag = computed
+        }
+        _ => return Err(RouteWeaverError::UnexpectedSegment),
+    }
+
+    Ok(reply)
+}
+
+/// Make sure a reply about one of our messages really came from `replier`
+pub fn verify_reply(
+    key: &PrivateKey,
+    replier: &PublicKey,
+    reply: &MessageSegment,
+) -> Result<(), RouteWeaverError> {
+    let (MessageSegment::Acknowledge { tag, .. } | MessageSegment::MissingSegments { tag, .. }) =
+        reply
+    else {
+        return Err(RouteWeaverError::UnexpectedSegment);
+    };
+
+    // Compared in constant time
+    reply_mac(key, replier, replier, reply)?
+        .verify_slice(tag)
+        .map_err(|_| RouteWeaverError::ForgedReply(*replier))
+}
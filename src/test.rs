@@ -1,4 +1,2638 @@
+use crate::{
+    abuse::AbuseTracker,
+    admin::{send_admin_command, serve_admin_socket},
+    compression::{CompressionPolicy, ZstdDictionaries},
+    config::{default_max_decompressed_message_size, Config, DenyListEntry, TransportConfig},
+    delivery::{DeliveryTracker, MAX_DELIVERY_ATTEMPTS},
+    error::RouteWeaverError,
+    identity,
+    limited::LimitedVec,
+    peer::{create_keypair, derive_public_key, perform_handshake, ConnectedPeer, Session},
+    proto::{
+        Address, DictionaryId, Message, MessageCompressionMode, MessageId, MessageSegment, Packet,
+        Peer, PrivateKey, Protocol, PublicKey, BINCODE_MESSAGE_CONFIG,
+        MAX_DECOMPRESSED_MESSAGE_SIZE, MAX_MESSAGE_SEGMENT_SIZE, PROTOCOL_EDITION,
+    },
+    reassembly::{MessageSegmentData, MessageTracker, ReassemblyLimits},
+    routing::RoutingTable,
+    runtime::{
+        compress_message, decode_message, decompress_message, dial_seeders,
+        encode_clear_text_message, handle_message, maintain_connection_to_peer, manage_transports,
+        packet_listener, packet_writer, reload_config, route_encoded_message, segment_message,
+        send_encoded_message, shutdown, start_transport, ClearTextMessage, EncodedMessage,
+        PendingDeliveries, Relay, RuntimeContext,
+    },
+    seal::{open, seal, tag_reply, verify_reply},
+    transport::{
+        frame_checksum, http::HttpTransport, irc::IrcTransport, tcp::TcpTransport,
+        unix::UnixTransport, PacketEncoderDecoder, PlainBincodePacketReader,
+        PlainBincodePacketWriter, Transport, TransportReader, TransportWriter, FRAME_HEADER_SIZE,
+    },
+};
+use bincode::serde::encode_to_vec;
+use blake2::{Blake2s256, Digest};
+use bytes::{BufMut, BytesMut};
+use dashmap::DashMap;
+use deadqueue::unlimited::Queue;
+use futures_util::{SinkExt, StreamExt};
+use itertools::Itertools;
+use std::{
+    collections::{BTreeMap, HashSet},
+    path::Path,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc,
+    },
+    time::{Duration, Instant},
+};
+use tokio::{
+    io::{
+        duplex, split, AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader, DuplexStream,
+        ReadHalf, WriteHalf,
+    },
+    net::{TcpStream, UnixListener, UnixStream},
+    sync::{
+        mpsc::{channel, unbounded_channel, Receiver, Sender},
+        oneshot, watch,
+    },
+    task::JoinHandle,
+    time::timeout,
+};
+use tokio_util::{codec::Decoder, sync::CancellationToken, task::TaskTracker};
+
+type DuplexReader = PlainBincodePacketReader<ReadHalf<DuplexStream>>;
+type DuplexWriter = PlainBincodePacketWriter<WriteHalf<DuplexStream>>;
+
+/// Both ends of an in memory pipe carrying plain packets
+fn create_pipe() -> ((DuplexReader, DuplexWriter), (DuplexReader, DuplexWriter)) {
+    let (left, right) = duplex(1024 * 1024);
+    let (left_reader, left_writer) = split(left);
+    let (right_reader, right_writer) = split(right);
+
+    (
+        (
+            PlainBincodePacketReader::new(left_reader),
+            PlainBincodePacketWriter::new(left_writer),
+        ),
+        (
+            PlainBincodePacketReader::new(right_reader),
+            PlainBincodePacketWriter::new(right_writer),
+        ),
+    )
+}
+
+/// Connect two identities over an in memory pipe and run the handshake between them
+async fn create_session_pair(
+    initiator_private_key: &PrivateKey,
+    responder_private_key: &PrivateKey,
+) -> (
+    (PublicKey, DuplexReader, DuplexWriter, Session),
+    (PublicKey, DuplexReader, DuplexWriter, Session),
+) {
+    create_advertising_session_pair(initiator_private_key, responder_private_key, &[], u64::MAX)
+        .await
+}
+
+/// Like `create_session_pair`, with the initiator advertising dictionaries and a message size limit
+async fn create_advertising_session_pair(
+    initiator_private_key: &PrivateKey,
+    responder_private_key: &PrivateKey,
+    dictionaries: &[DictionaryId],
+    max_message_size: u64,
+) -> (
+    (PublicKey, DuplexReader, DuplexWriter, Session),
+    (PublicKey, DuplexReader, DuplexWriter, Session),
+) {
+    let (
+        (mut initiator_reader, mut initiator_writer),
+        (mut responder_reader, mut responder_writer),
+    ) = create_pipe();
+
+    let (initiator, responder) = tokio::join!(
+        perform_handshake(
+            &mut initiator_reader,
+            &mut initiator_writer,
+            initiator_private_key,
+            true,
+            dictionaries,
+            max_message_size
+        ),
+        perform_handshake(
+            &mut responder_reader,
+            &mut responder_writer,
+            responder_private_key,
+            false,
+            &[],
+            u64::MAX
+        ),
+    );
+
+    let (responder_public_key, initiator_session) = initiator.unwrap();
+    let (initiator_public_key, responder_session) = responder.unwrap();
+
+    (
+        (
+            initiator_public_key,
+            initiator_reader,
+            initiator_writer,
+            initiator_session,
+        ),
+        (
+            responder_public_key,
+            responder_reader,
+            responder_writer,
+            responder_session,
+        ),
+    )
+}
+
+/// Runtime state for a node that isn't running anything yet
+fn create_context(config: Config) -> RuntimeContext {
+    RuntimeContext {
+        config: Arc::new(watch::Sender::new(Arc::new(config))),
+        message_queue: Arc::new(Queue::new()),
+        message_tracker: Arc::new(MessageTracker::default()),
+        deliveries: Arc::new(DeliveryTracker::default()),
+        abuse: Arc::new(AbuseTracker::default()),
+        session_tracker: Arc::new(DashMap::new()),
+        routing_table: Arc::new(RoutingTable::default()),
+        dictionaries: Arc::new(ZstdDictionaries::default()),
+        encoded_message_sender: channel(1).0,
+        relayed_segment_sender: channel(1).0,
+        shutdown: CancellationToken::new(),
+        tasks: TaskTracker::new(),
+    }
+}
+
+/// Dial a node over its Unix socket and listen for whatever it sends back once it knows us
+async fn connect_unix_client(
+    context: &RuntimeContext,
+    socket_path: &Path,
+    private_key: Arc<PrivateKey>,
+) -> (Receiver<EncodedMessage>, JoinHandle<()>) {
+    let stream = loop {
+        if let Ok(stream) = UnixStream::connect(socket_path).await {
+            break stream;
+        }
+
+        tokio::time::sleep(Duration::from_millis(10)).await;
+    };
+    let (reader, writer) = stream.into_split();
+    let mut reader = PlainBincodePacketReader::new(reader);
+    let mut writer = PlainBincodePacketWriter::new(writer);
+
+    let (_, session) =
+        perform_handshake(&mut reader, &mut writer, &private_key, true, &[], u64::MAX)
+            .await
+            .unwrap();
+
+    let public_key = derive_public_key(&private_key);
+    while !context.session_tracker.contains_key(&public_key) {
+        tokio::time::sleep(Duration::from_millis(10)).await;
+    }
+
+    let (complete_message_receiver, listener, _) = spawn_session_endpoint(
+        public_key,
+        private_key,
+        reader,
+        writer,
+        session,
+        Arc::new(DeliveryTracker::default()),
+    );
+
+    (complete_message_receiver, listener)
+}
+
+/// Read and write a session like a node would, answering reliable messages without relaying any
+fn spawn_session_endpoint(
+    local_key: PublicKey,
+    private_key: Arc<PrivateKey>,
+    reader: impl TransportReader + 'static,
+    writer: impl TransportWriter + 'static,
+    session: Session,
+    deliveries: PendingDeliveries,
+) -> (Receiver<EncodedMessage>, JoinHandle<()>, Sender<Packet>) {
+    let session = Arc::new(session);
+    let (packet_sender, packet_receiver) = channel(1024);
+    let (complete_message_sender, complete_message_receiver) = channel(1024);
+
+    // Hangs up once the listener and whoever holds the sender are done with it
+    tokio::spawn(packet_writer(writer, packet_receiver, session.clone()));
+    let listener = tokio::spawn(packet_listener(
+        reader,
+        session,
+        complete_message_sender,
+        Arc::new(MessageTracker::default()),
+        Some(Relay {
+            local_key,
+            private_key,
+            sender: packet_sender.clone(),
+            deliveries,
+            neighbors: Arc::new(DashMap::new()),
+            abuse: Arc::new(AbuseTracker::default()),
+        }),
+    ));
+
+    (complete_message_receiver, listener, packet_sender)
+}
+
+/// Push packets through a real session opened by `source` and collect whatever the listener reassembles
+async fn send_through_listener(
+    source: &PrivateKey,
+    packets: Vec<Packet>,
+) -> Option<EncodedMessage> {
+    collect_from_listener(source, packets, 1).await.pop()
+}
+
+/// Like `send_through_listener`, but waits for up to `count` complete messages
+async fn collect_from_listener(
+    source: &PrivateKey,
+    packets: Vec<Packet>,
+    count: usize,
+) -> Vec<EncodedMessage> {
+    let ((_, _, writer, sender_session), (_, reader, _, receiver_session)) =
+        create_session_pair(source, &create_keypair().1).await;
+
+    let (packet_sender, packet_receiver) = channel(1024);
+    let (complete_message_sender, mut complete_message_receiver) = channel(1024);
+
+    tokio::spawn(packet_writer(
+        writer,
+        packet_receiver,
+        Arc::new(sender_session),
+    ));
+    tokio::spawn(packet_listener(
+        reader,
+        Arc::new(receiver_session),
+        complete_message_sender,
+        Arc::new(MessageTracker::default()),
+        None,
+    ));
+
+    for packet in packets {
+        packet_sender.send(packet).await.unwrap();
+    }
+
+    let mut received = Vec::new();
+    while received.len() < count {
+        match timeout(Duration::from_secs(1), complete_message_receiver.recv()).await {
+            Ok(Some(message)) => received.push(message),
+            _ => break,
+        }
+    }
+
+    received
+}
+
 #[tokio::test]
 async fn packet_test() {
+    let (source, source_private_key) = create_keypair();
+    let (destination, _) = create_keypair();
+
+    let message = Message::PeersList {
+        peers: HashSet::from(["tcp@127.0.0.1".parse().unwrap()]),
+        routes: BTreeMap::new(),
+    };
+    let (_, data) = compress_message(&message, &CompressionPolicy::default(), None).unwrap();
+
+    let packets =
+        segment_message(source, destination, &data, MAX_MESSAGE_SEGMENT_SIZE, false).unwrap();
+    assert_eq!(packets.len(), 2);
+
+    let received = send_through_listener(&source_private_key, packets)
+        .await
+        .unwrap();
+
+    assert_eq!(received.claimed_source, source);
+    assert_eq!(received.claimed_destination, destination);
+    assert_eq!(received.message, data);
+}
+
+#[tokio::test]
+async fn multiple_segment_packet_test() {
+    let (source, source_private_key) = create_keypair();
+    let (destination, _) = create_keypair();
+
+    let data = (0..=u8::MAX).cycle().take(1000).collect_vec();
+
+    let packets = segment_message(source, destination, &data, 64, false).unwrap();
+    // 16 segments and the end message
+    assert_eq!(packets.len(), 17);
+
+    let received = send_through_listener(&source_private_key, packets)
+        .await
+        .unwrap();
+    assert_eq!(received.message, data);
+
+    // Segments arriving out of order are still put back together
+    let mut packets = segment_message(source, destination, &data, 64, false).unwrap();
+    packets[..16].reverse();
+
+    let received = send_through_listener(&source_private_key, packets)
+        .await
+        .unwrap();
+    assert_eq!(received.message, data);
+}
+
+#[tokio::test]
+async fn missing_segment_packet_test() {
+    let (source, source_private_key) = create_keypair();
+    let (destination, _) = create_keypair();
+
+    let data = vec![0; 1000];
+
+    let mut packets = segment_message(source, destination, &data, 64, false).unwrap();
+    packets.remove(3);
+
+    assert!(send_through_listener(&source_private_key, packets)
+        .await
+        .is_none());
+}
+
+#[tokio::test]
+async fn interleaved_messages_test() {
+    let (source, source_private_key) = create_keypair();
+    let (destination, _) = create_keypair();
+
+    let first = segment_message(source, destination, &[1; 300], 64, false).unwrap();
+    let second = segment_message(source, destination, &[2; 300], 64, false).unwrap();
+
+    // Alternate between the two messages as if they had taken different links
+    let packets = first.into_iter().interleave(second).collect_vec();
+
+    let received = collect_from_listener(&source_private_key, packets, 2).await;
+
+    assert_eq!(received.len(), 2);
+    assert_eq!(received[0].message, vec![1; 300]);
+    assert_eq!(received[1].message, vec![2; 300]);
+}
+
+#[tokio::test]
+async fn many_segments_test() {
+    let (source, source_private_key) = create_keypair();
+    let (destination, _) = create_keypair();
+
+    // Far more segments than a single byte could count
+    let data = (0..1000 * 64).map(|i| (i % 251) as u8).collect_vec();
+    let packets = segment_message(source, destination, &data, 64, false).unwrap();
+    assert_eq!(packets.len(), 1001);
+    assert!(matches!(
+        packets[999].message,
+        MessageSegment::Message { index: 999, .. }
+    ));
+
+    let received = send_through_listener(&source_private_key, packets)
+        .await
+        .unwrap();
+    assert_eq!(received.message, data);
+}
+
+#[tokio::test]
+async fn large_message_test() {
+    let (source, source_private_key) = create_keypair();
+    let (destination, destination_private_key) = create_keypair();
+
+    // Far more than fits in a single segment, even once compressed
+    let message = Message::PeersList {
+        peers: HashSet::new(),
+        routes: (0..4000).map(|_| (create_keypair().0, 1)).collect(),
+    };
+    let (compression_mode, data) =
+        compress_message(&message, &CompressionPolicy::default(), None).unwrap();
+    assert!(data.len() > MAX_MESSAGE_SEGMENT_SIZE * 2);
+
+    let sealed = seal(&source_private_key, &destination, compression_mode, &data).unwrap();
+    let packets = segment_message(
+        source,
+        destination,
+        &sealed,
+        MAX_MESSAGE_SEGMENT_SIZE,
+        false,
+    )
+    .unwrap();
+
+    let received = send_through_listener(&source_private_key, packets)
+        .await
+        .unwrap();
+    let Message::PeersList { routes, .. } = decode_message(
+        &received,
+        &destination_private_key,
+        default_max_decompressed_message_size(),
+        &ZstdDictionaries::default(),
+    )
+    .unwrap() else {
+        panic!("Decoded to the wrong message");
+    };
+    assert_eq!(routes.len(), 4000);
+}
+
+#[tokio::test]
+async fn path_limit_test() {
+    let (public_key, private_key) = create_keypair();
+    let (neighbor, _) = create_keypair();
+    let (far_away, _) = create_keypair();
+
+    let config: Config = toml::from_str(&format!(
+        r#"
+        public_key = "{public_key}"
+        private_key = "{private_key}"
+        "#
+    ))
+    .unwrap();
+    let context = create_context(config);
+
+    let (packet_sender, mut packet_receiver) = channel(1024);
+    context.session_tracker.insert(
+        neighbor,
+        ConnectedPeer {
+            packet_sender,
+            segment_size: 64,
+            compression: Arc::new(CompressionPolicy::default()),
+            dictionary: None,
+            max_message_size: 1000,
+            peer: None,
+            disconnect: CancellationToken::new(),
+        },
+    );
+    context
+        .routing_table
+        .update_routes_via(public_key, neighbor, &BTreeMap::from([(far_away, 1)]));
+
+    // The neighbor won't take it, and we hear about it before anything is sent
+    assert!(matches!(
+        send_encoded_message(&context, public_key, neighbor, &[0; 2000]).await,
+        Err(RouteWeaverError::MessageTooLargeForPath {
+            size: 2000,
+            limit: 1000
+        })
+    ));
+    assert!(packet_receiver.try_recv().is_err());
+
+    // Relaying through it is fine, as only the destination holds on to the whole message
+    send_encoded_message(&context, public_key, far_away, &[0; 2000])
+        .await
+        .unwrap();
+    assert_eq!(packet_receiver.len(), 2000 / 64 + 2);
+}
+
+#[tokio::test]
+async fn relay_test() {
+    let (local_key, local_private_key) = create_keypair();
+    let ((_, _, writer, sender_session), (_, reader, _, receiver_session)) =
+        create_session_pair(&create_keypair().1, &local_private_key).await;
+    let (source, _) = create_keypair();
+    let (far_away, _) = create_keypair();
+
+    let (packet_sender, packet_receiver) = channel(1024);
+    let (complete_message_sender, mut complete_message_receiver) = channel(1024);
+    let (relayed_segment_sender, mut relayed_segment_receiver) = channel(1024);
+
+    tokio::spawn(packet_writer(
+        writer,
+        packet_receiver,
+        Arc::new(sender_session),
+    ));
+    tokio::spawn(packet_listener(
+        reader,
+        Arc::new(receiver_session),
+        complete_message_sender,
+        Arc::new(MessageTracker::default()),
+        Some(Relay {
+            local_key,
+            private_key: Arc::new(local_private_key),
+            sender: relayed_segment_sender,
+            deliveries: Arc::new(DeliveryTracker::default()),
+            neighbors: Arc::new(DashMap::new()),
+            abuse: Arc::new(AbuseTracker::default()),
+        }),
+    ));
+
+    let relayed = segment_message(source, far_away, &[1; 200], 64, false).unwrap();
+    let local = segment_message(source, local_key, &[2; 200], 64, false).unwrap();
+    let expected = relayed
+        .iter()
+        .map(|packet| encode_to_vec(&packet.message, BINCODE_MESSAGE_CONFIG).unwrap())
+        .collect_vec();
+    for packet in relayed.into_iter().chain(local) {
+        packet_sender.send(packet).await.unwrap();
+    }
+
+    // Segments for someone else come out one by one, untouched
+    for expected in expected {
+        let packet = timeout(Duration::from_secs(1), relayed_segment_receiver.recv())
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(packet.source, source);
+        assert_eq!(packet.destination, far_away);
+        assert_eq!(
+            encode_to_vec(&packet.message, BINCODE_MESSAGE_CONFIG).unwrap(),
+            expected
+        );
+    }
+
+    let received = timeout(Duration::from_secs(1), complete_message_receiver.recv())
+        .await
+        .unwrap()
+        .unwrap();
+    assert_eq!(received.claimed_destination, local_key);
+    assert_eq!(received.message, vec![2; 200]);
+    assert!(relayed_segment_receiver.try_recv().is_err());
+}
+
+#[tokio::test]
+async fn impersonation_test() {
+    let (local_key, local_private_key) = create_keypair();
+    let ((_, _, writer, sender_session), (_, reader, _, receiver_session)) =
+        create_session_pair(&create_keypair().1, &local_private_key).await;
+    let (neighbor, _) = create_keypair();
+    let (far_away, far_away_private_key) = create_keypair();
+
+    let (packet_sender, packet_receiver) = channel(1024);
+    let (complete_message_sender, mut complete_message_receiver) = channel(1024);
+    let (relayed_sender, mut relayed_receiver) = channel(1024);
+    let deliveries = Arc::new(DeliveryTracker::default());
+    let neighbors = Arc::new(DashMap::new());
+    let abuse = Arc::new(AbuseTracker::default());
+    let local_private_key = Arc::new(local_private_key);
+
+    neighbors.insert(
+        neighbor,
+        ConnectedPeer {
+            packet_sender: channel(1).0,
+            segment_size: 64,
+            compression: Arc::new(CompressionPolicy::default()),
+            dictionary: None,
+            max_message_size: 1000,
+            peer: None,
+            disconnect: CancellationToken::new(),
+        },
+    );
+
+    tokio::spawn(packet_writer(
+        writer,
+        packet_receiver,
+        Arc::new(sender_session),
+    ));
+    tokio::spawn(packet_listener(
+        reader,
+        Arc::new(receiver_session),
+        complete_message_sender,
+        Arc::new(MessageTracker::default()),
+        Some(Relay {
+            local_key,
+            private_key: local_private_key.clone(),
+            sender: relayed_sender,
+            deliveries: deliveries.clone(),
+            neighbors,
+            abuse: abuse.clone(),
+        }),
+    ));
+
+    // Neither we nor a neighbor would send by way of someone else, to us or onwards
+    let forged = segment_message(neighbor, local_key, &[1; 100], 64, false)
+        .unwrap()
+        .into_iter()
+        .chain(segment_message(local_key, local_key, &[2; 100], 64, false).unwrap())
+        .chain(segment_message(local_key, far_away, &[5; 100], 64, false).unwrap())
+        .chain(segment_message(neighbor, far_away, &[6; 100], 64, false).unwrap());
+    let honest = segment_message(far_away, local_key, &[3; 100], 64, false).unwrap();
+    for packet in forged.chain(honest) {
+        packet_sender.send(packet).await.unwrap();
+    }
+
+    let received = timeout(Duration::from_secs(1), complete_message_receiver.recv())
+        .await
+        .unwrap()
+        .unwrap();
+    assert_eq!(received.claimed_source, far_away);
+    assert_eq!(abuse.total(), 12);
+    // Passed on, the destination would have held whoever claimed to be us to account for them
+    assert!(relayed_receiver.try_recv().is_err());
+
+    // Only the destination of a message can acknowledge it
+    let packets = segment_message(local_key, far_away, &[4; 100], 64, true).unwrap();
+    let MessageSegment::EndMessage { id, .. } = packets.last().unwrap().message else {
+        unreachable!()
+    };
+    let (confirmation, confirmed) = oneshot::channel();
+    deliveries.track(&packets, confirmation);
+
+    let forged = MessageSegment::Acknowledge { id, tag: [0; 32] };
+    let turned_around =
+        tag_reply(&local_private_key, &local_key, &far_away, forged.clone()).unwrap();
+    assert!(verify_reply(&local_private_key, &far_away, &turned_around).is_err());
+    let honest = tag_reply(&far_away_private_key, &far_away, &local_key, forged.clone()).unwrap();
+
+    for message in [forged, turned_around, honest] {
+        packet_sender
+            .send(Packet {
+                source: far_away,
+                destination: local_key,
+                message,
+            })
+            .await
+            .unwrap();
+    }
+
+    timeout(Duration::from_secs(1), confirmed)
+        .await
+        .unwrap()
+        .unwrap()
+        .unwrap();
+    assert_eq!(abuse.total(), 14);
+}
+
+#[tokio::test]
+async fn forged_source_test() {
+    let (local_key, local_private_key) = create_keypair();
+    let (relay_key, relay_private_key) = create_keypair();
+    let (far_away, _) = create_keypair();
+    let (_, forger_private_key) = create_keypair();
+
+    let config: Config =
+        toml::from_str(&format!(r#"private_key = "{local_private_key}""#)).unwrap();
+    let context = create_context(config);
+    let ((_, _, writer, relay_session), (_, reader, _, local_session)) =
+        create_session_pair(&relay_private_key, &local_private_key).await;
+
+    let (packet_sender, packet_receiver) = channel(1024);
+    let (encoded_message_sender, encoded_message_receiver) = channel(1024);
+
+    tokio::spawn(packet_writer(
+        writer,
+        packet_receiver,
+        Arc::new(relay_session),
+    ));
+    tokio::spawn(packet_listener(
+        reader,
+        Arc::new(local_session),
+        encoded_message_sender,
+        context.message_tracker.clone(),
+        Some(Relay {
+            local_key,
+            private_key: context.config().private_key.clone(),
+            sender: channel(1024).0,
+            deliveries: context.deliveries.clone(),
+            neighbors: context.session_tracker.clone(),
+            abuse: context.abuse.clone(),
+        }),
+    ));
+    tokio::spawn(route_encoded_message(
+        context.clone(),
+        encoded_message_receiver,
+    ));
+
+    let forge = |source| {
+        let sealed = seal(&forger_private_key, &local_key, None, &[0]).unwrap();
+        segment_message(source, local_key, &sealed, 64, false).unwrap()
+    };
+
+    // The relay passes on a forgery it had no way of spotting, then forges one under its own name
+    for packet in forge(far_away).into_iter().chain(forge(relay_key)) {
+        packet_sender.send(packet).await.unwrap();
+    }
+
+    timeout(Duration::from_secs(1), async {
+        while context.abuse.total() == 0 {
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+    })
+    .await
+    .unwrap();
+    assert_eq!(context.abuse.total(), 1);
+}
+
+#[tokio::test]
+async fn reliable_delivery_test() {
+    let (_, alice_private_key) = create_keypair();
+    let (_, bob_private_key) = create_keypair();
+    let (
+        (alice, alice_reader, alice_writer, alice_session),
+        (bob, bob_reader, bob_writer, bob_session),
+    ) = create_session_pair(&alice_private_key, &bob_private_key).await;
+    let deliveries = Arc::new(DeliveryTracker::default());
+
+    let (_, _, alice_sender) = spawn_session_endpoint(
+        alice,
+        Arc::new(alice_private_key),
+        alice_reader,
+        alice_writer,
+        alice_session,
+        deliveries.clone(),
+    );
+    let (mut bob_receiver, _, _) = spawn_session_endpoint(
+        bob,
+        Arc::new(bob_private_key),
+        bob_reader,
+        bob_writer,
+        bob_session,
+        Arc::new(DeliveryTracker::default()),
+    );
+
+    let data = (0..1000).map(|i| i as u8).collect_vec();
+    let mut packets = segment_message(alice, bob, &data, 64, true).unwrap();
+    let (confirmation, confirmed) = oneshot::channel();
+    deliveries.track(&packets, confirmation);
+
+    // Bob asks for the lost segments once the end arrives, and only those are sent again
+    packets.remove(9);
+    packets.remove(3);
+    for packet in packets {
+        alice_sender.send(packet).await.unwrap();
+    }
+
+    let received = timeout(Duration::from_secs(1), bob_receiver.recv())
+        .await
+        .unwrap()
+        .unwrap();
+    assert_eq!(received.message, data);
+
+    timeout(Duration::from_secs(1), confirmed)
+        .await
+        .unwrap()
+        .unwrap()
+        .unwrap();
+    assert_eq!(deliveries.pending_messages(), 0);
+
+    // Without an answer the sender gives up after so many rounds and says so
+    let packets = segment_message(alice, bob, &data, 64, true).unwrap();
+    let MessageSegment::EndMessage { id, .. } = packets.last().unwrap().message else {
+        unreachable!()
+    };
+    let (confirmation, mut confirmed) = oneshot::channel();
+    deliveries.track(&packets, confirmation);
+
+    let resent = deliveries.retransmit(&(bob, id), &[0, 5, 99]);
+    assert_eq!(resent.len(), 3);
+    assert!(matches!(
+        resent[1].message,
+        MessageSegment::Message { index: 5, .. }
+    ));
+    assert!(matches!(
+        resent[2].message,
+        MessageSegment::EndMessage { .. }
+    ));
+
+    for _ in 2..MAX_DELIVERY_ATTEMPTS {
+        assert!(confirmed.try_recv().is_err());
+        deliveries.retransmit(&(bob, id), &[]);
+    }
+    assert!(deliveries.retransmit(&(bob, id), &[]).is_empty());
+    assert!(matches!(
+        confirmed.try_recv().unwrap(),
+        Err(RouteWeaverError::Unacknowledged {
+            attempts: MAX_DELIVERY_ATTEMPTS,
+            ..
+        })
+    ));
+}
+
+#[tokio::test]
+async fn unroutable_delivery_test() {
+    let (_, private_key) = create_keypair();
+    let (destination, _) = create_keypair();
+    let config: Config = toml::from_str(&format!(r#"private_key = "{private_key}""#)).unwrap();
+    let context = create_context(config);
+    context
+        .tasks
+        .spawn(encode_clear_text_message(context.clone()));
+
+    // Nobody to hand it to, which the sender hears about rather than the confirmation just going away
+    let (confirmation, confirmed) = oneshot::channel();
+    context.message_queue.push(ClearTextMessage {
+        destination,
+        message: Message::Goodbye,
+        confirmation: Some(confirmation),
+    });
+    assert!(matches!(
+        timeout(Duration::from_secs(5), confirmed).await.unwrap(),
+        Ok(Err(RouteWeaverError::NoRoute))
+    ));
+
+    shutdown(&context).await;
+}
+
+#[test]
+fn missing_segments_test() {
+    let (source, _) = create_keypair();
+    let (destination, _) = create_keypair();
+    let tracker = MessageTracker::default();
+    let key = (source, destination, MessageId(1));
+
+    // Nothing received means everything is missing
+    assert_eq!(tracker.missing_segments(&key, 3), vec![0, 1, 2]);
+
+    tracker.insert_segment(key, source, 0, LimitedVec(vec![1; 10]));
+    tracker.insert_segment(key, source, 2, LimitedVec(vec![3; 10]));
+    tracker.insert_segment(key, source, 4, LimitedVec(vec![5; 10]));
+    assert_eq!(tracker.missing_segments(&key, 6), vec![1, 3, 5]);
+
+    assert!(!tracker.was_delivered(&key));
+    tracker.mark_delivered(key);
+    assert!(tracker.was_delivered(&key));
+}
+
+#[test]
+fn reassembly_limits_test() {
+    let (hostile, _) = create_keypair();
+    let (honest, _) = create_keypair();
+    let (destination, _) = create_keypair();
+
+    let tracker = MessageTracker::new(ReassemblyLimits {
+        message_timeout: Duration::from_millis(50),
+        max_bytes_per_source: 300,
+        max_total_bytes: 500,
+        max_messages: 1024,
+    });
+
+    let id = MessageId(1);
+
+    // A source that never finishes only ever gets to hold its own budget
+    for index in 0..4 {
+        tracker.insert_segment(
+            (hostile, destination, id),
+            hostile,
+            index,
+            LimitedVec(vec![0; 100]),
+        );
+    }
+    assert_eq!(tracker.pending_bytes(), 100);
+    assert_eq!(tracker.evictions(), 1);
+
+    tracker.insert_segment(
+        (hostile, destination, id),
+        hostile,
+        4,
+        LimitedVec(vec![0; 200]),
+    );
+    tracker.insert_segment(
+        (honest, destination, id),
+        honest,
+        0,
+        LimitedVec(vec![0; 100]),
+    );
+    assert_eq!(tracker.pending_bytes(), 400);
+
+    // Crossing the global cap pushes out the oldest message first
+    tracker.insert_segment((honest, hostile, id), honest, 0, LimitedVec(vec![0; 200]));
+    assert_eq!(tracker.pending_bytes(), 300);
+    assert!(tracker.take(&(hostile, destination, id)).is_none());
+    assert_eq!(tracker.evictions(), 2);
+
+    std::thread::sleep(Duration::from_millis(100));
+    tracker.expire();
+    assert_eq!(tracker.pending_bytes(), 0);
+    assert_eq!(tracker.evictions(), 4);
+}
+
+#[test]
+fn neighbor_reassembly_budget_test() {
+    let (neighbor, _) = create_keypair();
+    let (honest, _) = create_keypair();
+    let (destination, _) = create_keypair();
+
+    let tracker = MessageTracker::new(ReassemblyLimits {
+        max_bytes_per_source: 300,
+        max_total_bytes: 1000,
+        ..Default::default()
+    });
+
+    let id = MessageId(1);
+    tracker.insert_segment(
+        (honest, destination, id),
+        honest,
+        1,
+        LimitedVec(vec![0; 100]),
+    );
+
+    // Claiming a fresh source for every message doesn't get a neighbor a fresh budget
+    for _ in 0..10 {
+        let (claimed, _) = create_keypair();
+        tracker.insert_segment(
+            (claimed, destination, id),
+            neighbor,
+            1,
+            LimitedVec(vec![0; 100]),
+        );
+    }
+    assert_eq!(tracker.pending_bytes(), 400);
+    assert_eq!(tracker.evictions(), 7);
+
+    // What other neighbors delivered is left alone
+    assert_eq!(
+        tracker.missing_segments(&(honest, destination, id), 2),
+        vec![0]
+    );
+}
+
+#[test]
+fn streaming_reassembly_test() {
+    let (source, _) = create_keypair();
+    let (destination, _) = create_keypair();
+    let tracker = MessageTracker::default();
+    let key = (source, destination, MessageId(1));
+
+    // Segments ahead of a gap wait for it to be filled
+    tracker.insert_segment(key, source, 2, LimitedVec(vec![3; 10]));
+    tracker.insert_segment(key, source, 0, LimitedVec(vec![1; 10]));
+    assert_eq!(tracker.pending_bytes(), 20);
+    tracker.insert_segment(key, source, 1, LimitedVec(vec![2; 10]));
+    assert_eq!(tracker.pending_bytes(), 30);
+
+    // Hashed segments can't be taken back
+    tracker.insert_segment(key, source, 0, LimitedVec(vec![9; 20]));
+    assert_eq!(tracker.pending_bytes(), 30);
+
+    let message = tracker.take(&key).unwrap();
+    let expected = [[1; 10], [2; 10], [3; 10]].concat();
+    assert_eq!(message.data, expected);
+    assert_eq!(
+        message.hash,
+        <[u8; 32]>::from(Blake2s256::digest(&expected))
+    );
+    assert_eq!(message.segments, 3);
+    assert_eq!(message.stray_segments, 0);
+    assert_eq!(tracker.pending_bytes(), 0);
+
+    tracker.insert_segment(key, source, 0, LimitedVec(vec![1; 10]));
+    tracker.insert_segment(key, source, 2, LimitedVec(vec![3; 10]));
+    let message = tracker.take(&key).unwrap();
+    assert_eq!(message.segments, 1);
+    assert_eq!(message.stray_segments, 1);
+}
+
+#[test]
+fn empty_segment_flood_test() {
+    let (source, _) = create_keypair();
+    let (destination, _) = create_keypair();
+    let tracker = MessageTracker::default();
+    let key = (source, destination, MessageId(1));
+
+    // Empty segments ahead of a gap cost nothing, so they aren't held on to
+    tracker.insert_segment(key, source, 0, LimitedVec(vec![1; 10]));
+    for index in 2..10_000 {
+        tracker.insert_segment(key, source, index, LimitedVec(Vec::new()));
+    }
+    for index in 0..1_000 {
+        let (other, _) = create_keypair();
+        tracker.insert_segment(
+            (other, destination, MessageId(1)),
+            source,
+            index + 1,
+            LimitedVec(Vec::new()),
+        );
+    }
+    assert_eq!(tracker.pending_messages(), 1);
+    assert_eq!(tracker.missing_segments(&key, 4), vec![1, 2, 3]);
+
+    // An empty message is still taken in
+    let empty = (source, destination, MessageId(2));
+    tracker.insert_segment(empty, source, 0, LimitedVec(Vec::new()));
+    assert_eq!(tracker.take(&empty).unwrap().segments, 1);
+
+    let message = tracker.take(&key).unwrap();
+    assert_eq!(message.segments, 1);
+    assert_eq!(message.stray_segments, 0);
+}
+
+/// Reassembly as it used to be, every segment kept apart until the end then hashed and copied
+fn buffered_reassembly(segments: Vec<(u32, MessageSegmentData)>) -> (Vec<u8>, [u8; 32]) {
+    let stored = DashMap::new();
+    for (index, segment) in segments {
+        stored.insert(index, segment);
+    }
+
+    let sorted = stored.into_iter().collect::<BTreeMap<_, _>>();
+
+    let mut hasher = Blake2s256::default();
+    for segment in sorted.values() {
+        hasher.update(&segment.0);
+    }
+
+    let mut final_buffer = Vec::new();
+    for segment in sorted.into_values() {
+        final_buffer.extend_from_slice(&segment.0);
+    }
+
+    (final_buffer, hasher.finalize().into())
+}
+
+// cargo test --release reassembly_benchmark -- --ignored --nocapture
+#[test]
+#[ignore]
+fn reassembly_benchmark() {
+    const ROUNDS: usize = 20;
+    const SEGMENTS: u32 = 256;
+
+    let (source, _) = create_keypair();
+    let (destination, _) = create_keypair();
+    let segment = vec![7; MAX_MESSAGE_SEGMENT_SIZE];
+    let bytes = (ROUNDS * SEGMENTS as usize * MAX_MESSAGE_SEGMENT_SIZE) as f64;
+    let make_segments = || {
+        (0..SEGMENTS)
+            .map(|index| (index, LimitedVec(segment.clone())))
+            .collect_vec()
+    };
+
+    let mut buffered = Duration::ZERO;
+    let mut streaming = Duration::ZERO;
+
+    for round in 0..ROUNDS {
+        let segments = make_segments();
+        let started = Instant::now();
+        let (data, _) = buffered_reassembly(segments);
+        buffered += started.elapsed();
+        assert_eq!(data.len(), SEGMENTS as usize * MAX_MESSAGE_SEGMENT_SIZE);
+
+        let tracker = MessageTracker::new(ReassemblyLimits {
+            max_bytes_per_source: usize::MAX,
+            max_total_bytes: usize::MAX,
+            ..Default::default()
+        });
+        let key = (source, destination, MessageId(round as u64));
+        let segments = make_segments();
+        let started = Instant::now();
+        for (index, segment) in segments {
+            tracker.insert_segment(key, source, index, segment);
+        }
+        let message = tracker.take(&key).unwrap();
+        streaming += started.elapsed();
+        assert_eq!(
+            message.data.len(),
+            SEGMENTS as usize * MAX_MESSAGE_SEGMENT_SIZE
+        );
+    }
+
+    println!(
+        "buffered: {:?} ({:.0} MiB/s), streaming: {:?} ({:.0} MiB/s)",
+        buffered,
+        bytes / buffered.as_secs_f64() / (1024.0 * 1024.0),
+        streaming,
+        bytes / streaming.as_secs_f64() / (1024.0 * 1024.0)
+    );
+}
+
+#[test]
+fn frame_resync_test() {
+    let (source, _) = create_keypair();
+    let (destination, _) = create_keypair();
+
+    let packets = segment_message(source, destination, &[7; 100], 64, false).unwrap();
+    let mut frames = packets
+        .into_iter()
+        .map(|packet| PacketEncoderDecoder::encode_frame(packet).unwrap())
+        .collect::<Vec<_>>();
+
+    // Flip a payload byte in the first frame and put line noise in front of everything
+    let last = frames[0].len() - 1;
+    frames[0][last] ^= 0xff;
+
+    let mut stream = b"noise".to_vec();
+    for frame in &frames {
+        stream.extend_from_slice(frame);
+    }
+
+    // Trickle the bytes in one at a time like a slow link would
+    let mut decoder = PacketEncoderDecoder;
+    let mut buffer = BytesMut::new();
+    let mut decoded = Vec::new();
+    for byte in stream {
+        buffer.put_u8(byte);
+
+        while let Some(packet) = decoder.decode(&mut buffer).unwrap() {
+            decoded.push(packet);
+        }
+    }
+
+    assert_eq!(decoded.len(), frames.len() - 1);
+    assert!(matches!(
+        decoded[0].message,
+        MessageSegment::Message { index: 1, .. }
+    ));
+    assert!(buffer.is_empty());
+}
+
+#[test]
+fn frame_edition_test() {
+    let (source, _) = create_keypair();
+    let (destination, _) = create_keypair();
+
+    let packet = segment_message(source, destination, &[], 64, false)
+        .unwrap()
+        .remove(0);
+    let mut frame = PacketEncoderDecoder::encode_frame(packet).unwrap();
+
+    // Rewrite the frame as if a peer from the next major version had sent it
+    let edition = PROTOCOL_EDITION.wrapping_add(1);
+    let checksum = frame_checksum(edition, &frame[FRAME_HEADER_SIZE..]);
+    frame[2] = edition;
+    frame[7..FRAME_HEADER_SIZE].copy_from_slice(&checksum);
+
+    assert!(matches!(
+        PacketEncoderDecoder.decode(&mut frame),
+        Err(RouteWeaverError::IncompatibleEdition(_))
+    ));
+}
+
+#[tokio::test]
+async fn handshake_test() {
+    let (initiator_public_key, initiator_private_key) = create_keypair();
+    let (responder_public_key, responder_private_key) = create_keypair();
+
+    let ((_, _, _, initiator_session), (_, _, _, responder_session)) =
+        create_advertising_session_pair(
+            &initiator_private_key,
+            &responder_private_key,
+            &[DictionaryId([1; 32])],
+            1234,
+        )
+        .await;
+
+    // Each side learns the other's key from the handshake itself
+    assert_eq!(initiator_session.remote_key, responder_public_key);
+    assert_eq!(responder_session.remote_key, initiator_public_key);
+    assert!(initiator_session.remote_dictionaries.is_empty());
+    assert_eq!(
+        responder_session.remote_dictionaries,
+        vec![DictionaryId([1; 32])]
+    );
+    assert_eq!(responder_session.remote_max_message_size, 1234);
+    assert_eq!(initiator_session.remote_max_message_size, u64::MAX);
+
+    let segment = MessageSegment::Message {
+        id: MessageId::random(),
+        index: 0,
+        data: LimitedVec(b"hello".to_vec()),
+    };
+
+    let encrypted = initiator_session.encrypt_segment(&segment).unwrap();
+    let MessageSegment::Encrypted { data } = &encrypted else {
+        panic!("Segment was not encrypted");
+    };
+    assert!(!data.0.windows(5).any(|window| window == b"hello"));
+
+    let MessageSegment::Message { index, data, .. } =
+        responder_session.decrypt_segment(encrypted).unwrap()
+    else {
+        panic!("Decrypted to the wrong segment type");
+    };
+    assert_eq!(index, 0);
+    assert_eq!(data.0, b"hello");
+
+    // Plaintext segments are refused once a session exists
+    assert!(responder_session.decrypt_segment(segment).is_err());
+}
+
+#[tokio::test]
+async fn tampered_handshake_test() {
+    let (_, initiator_private_key) = create_keypair();
+    let (_, responder_private_key) = create_keypair();
+
+    let ((mut initiator_reader, mut initiator_writer), mut towards_initiator) = create_pipe();
+    let ((mut responder_reader, mut responder_writer), mut towards_responder) = create_pipe();
+
+    // Passes the first message along untouched and flips a bit in the responder's answer
+    let tamperer = async move {
+        let first = towards_initiator.0.next().await.unwrap().unwrap();
+        towards_responder.1.send(first).await.unwrap();
+
+        let mut second = towards_responder.0.next().await.unwrap().unwrap();
+        let MessageSegment::Handshake { data } = &mut second.message else {
+            panic!("Responder did not answer with a handshake message");
+        };
+        *data.0.last_mut().unwrap() ^= 1;
+        towards_initiator.1.send(second).await.unwrap();
+    };
+
+    let (initiator, responder, _) = tokio::join!(
+        perform_handshake(
+            &mut initiator_reader,
+            &mut initiator_writer,
+            &initiator_private_key,
+            true,
+            &[],
+            u64::MAX
+        ),
+        perform_handshake(
+            &mut responder_reader,
+            &mut responder_writer,
+            &responder_private_key,
+            false,
+            &[],
+            u64::MAX
+        ),
+        tamperer,
+    );
+
+    // The initiator notices, and the responder never hears the final message
+    assert!(initiator.is_err());
+    assert!(responder.is_err());
+}
+
+#[test]
+fn seal_test() {
+    let (source, source_private_key) = create_keypair();
+    let (destination, destination_private_key) = create_keypair();
+    let (impostor, impostor_private_key) = create_keypair();
+
+    // Empty, a single chunk and several of them
+    for length in [0, 1000, 200 * 1024] {
+        let data = (0..length).map(|i| i as u8).collect_vec();
+        let sealed = seal(
+            &source_private_key,
+            &destination,
+            Some(MessageCompressionMode::Lz4),
+            &data,
+        )
+        .unwrap();
+
+        let (compression_mode, opened) = open(&destination_private_key, &source, &sealed).unwrap();
+        assert_eq!(compression_mode, Some(MessageCompressionMode::Lz4));
+        assert_eq!(opened, data);
+
+        // Relays can't read it, and can't make it pass for someone else's
+        assert!(open(&impostor_private_key, &source, &sealed).is_err());
+        assert!(matches!(
+            open(&destination_private_key, &impostor, &sealed),
+            Err(RouteWeaverError::ForgedSource(_))
+        ));
+    }
+
+    let data = vec![7; 100 * 1024];
+    let sealed = seal(&source_private_key, &destination, None, &data).unwrap();
+    assert!(!sealed.windows(64).any(|window| window == &data[..64]));
+
+    let mut tampered = sealed.clone();
+    *tampered.last_mut().unwrap() ^= 1;
+    assert!(open(&destination_private_key, &source, &tampered).is_err());
+
+    // Cutting the end off doesn't go unnoticed either
+    let truncated = &sealed[..sealed.len() - 1000];
+    assert!(matches!(
+        open(&destination_private_key, &source, truncated),
+        Err(RouteWeaverError::MalformedSeal)
+    ));
+    assert!(open(&destination_private_key, &source, &[]).is_err());
+
+    // Anyone can seal a message claiming to come from us, only we can make it open as ours
+    let forged = seal(&impostor_private_key, &destination, None, &data).unwrap();
+    assert!(open(&destination_private_key, &source, &forged).is_err());
+}
+
+#[test]
+fn compression_policy_test() {
+    let repetitive = b"routeweaver ".repeat(1000);
+    let noise = (0..4096).map(|_| rand::random::<u8>()).collect_vec();
+
+    for protocol in [Protocol::Tcp, Protocol::Irc] {
+        let (compression_mode, compressed) =
+            CompressionPolicy::default_for(protocol).compress(repetitive.clone(), None);
+        assert!(compressed.len() < repetitive.len());
+
+        assert_eq!(
+            decompress_message(
+                compression_mode,
+                compressed,
+                usize::MAX,
+                &ZstdDictionaries::default()
+            )
+            .unwrap(),
+            repetitive
+        );
+    }
+
+    assert!(matches!(
+        CompressionPolicy::default_for(Protocol::Irc)
+            .compress(repetitive.clone(), None)
+            .0,
+        Some(MessageCompressionMode::Zlib | MessageCompressionMode::Zstd { .. })
+    ));
+    assert_eq!(
+        CompressionPolicy::default_for(Protocol::Unix)
+            .compress(repetitive.clone(), None)
+            .0,
+        None
+    );
+    assert_eq!(CompressionPolicy::default().compress(noise, None).0, None);
+    assert_eq!(
+        CompressionPolicy::default().compress(vec![0; 16], None).0,
+        None
+    );
+
+    let config: TransportConfig = toml::from_str(r#"compression = ["lz4"]"#).unwrap();
+    let policy = CompressionPolicy::from_transport_config(Protocol::Irc, Some(&config)).unwrap();
+    assert_eq!(policy.modes, vec![MessageCompressionMode::Lz4]);
+
+    let config: TransportConfig = toml::from_str(r#"compression = ["brotli"]"#).unwrap();
+    assert!(CompressionPolicy::from_transport_config(Protocol::Tcp, Some(&config)).is_err());
+}
+
+#[test]
+fn zstd_dictionary_test() {
+    let (source, _) = create_keypair();
+    let (destination, _) = create_keypair();
+
+    let peers_list = |port: u16| {
+        encode_to_vec(
+            Message::PeersList {
+                peers: HashSet::from([format!("tcp@192.0.2.1:{port}").parse().unwrap()]),
+                routes: BTreeMap::from([(source, 1), (destination, 2)]),
+            },
+            BINCODE_MESSAGE_CONFIG,
+        )
+        .unwrap()
+    };
+
+    // Raw content dictionaries work as well as trained ones for a test
+    let mut dictionaries = ZstdDictionaries::default();
+    let id = dictionaries.insert((0..8).flat_map(&peers_list).collect());
+    assert_eq!(dictionaries.first_shared(&[id]), Some(id));
+    assert_eq!(dictionaries.first_shared(&[]), None);
+
+    let policy = CompressionPolicy {
+        modes: vec![MessageCompressionMode::Zstd { dictionary: None }],
+        min_length: 0,
+        ..Default::default()
+    };
+    let message = peers_list(3434);
+
+    let (plain_mode, plain) = policy.compress(message.clone(), None);
+    let (dictionary_mode, with_dictionary) =
+        policy.compress(message.clone(), dictionaries.get(&id));
+    assert_eq!(plain_mode, None);
+    assert_eq!(
+        dictionary_mode,
+        Some(MessageCompressionMode::Zstd {
+            dictionary: Some(id)
+        })
+    );
+    assert!(with_dictionary.len() < plain.len());
+
+    assert_eq!(
+        decompress_message(
+            dictionary_mode,
+            with_dictionary.clone(),
+            usize::MAX,
+            &dictionaries
+        )
+        .unwrap(),
+        message
+    );
+    // Without the dictionary there is nothing to decompress with
+    assert!(decompress_message(
+        dictionary_mode,
+        with_dictionary,
+        usize::MAX,
+        &ZstdDictionaries::default()
+    )
+    .is_err());
+}
+
+#[test]
+fn decompression_limit_test() {
+    let bomb = vec![0; 1024 * 1024];
+
+    let mut dictionaries = ZstdDictionaries::default();
+    let dictionary = vec![0; 4096];
+    let id = dictionaries.insert(dictionary.clone());
+
+    for (compression_mode, message) in [
+        (
+            MessageCompressionMode::Lz4,
+            lz4_flex::compress_prepend_size(&bomb),
+        ),
+        (
+            MessageCompressionMode::Zlib,
+            miniz_oxide::deflate::compress_to_vec_zlib(&bomb, 10),
+        ),
+        (
+            MessageCompressionMode::Zstd {
+                dictionary: Some(id),
+            },
+            zstd::bulk::Compressor::with_dictionary(19, &dictionary)
+                .unwrap()
+                .compress(&bomb)
+                .unwrap(),
+        ),
+    ] {
+        assert!(matches!(
+            decompress_message(Some(compression_mode), message, 64 * 1024, &dictionaries),
+            Err(RouteWeaverError::MessageTooLarge)
+        ));
+    }
+}
+
+#[tokio::test]
+async fn message_dispatch_test() {
+    let (public_key, private_key) = create_keypair();
+    let (requester, requester_private_key) = create_keypair();
+
+    let config: Config = toml::from_str(&format!(
+        r#"
+        public_key = "{public_key}"
+        private_key = "{private_key}"
+        "#
+    ))
+    .unwrap();
+    let context = create_context(config);
+
+    // Requests arrive compressed, sealed and encoded just like any other message
+    let (compression_mode, message) = compress_message(
+        &Message::RequestSystemInformation,
+        &CompressionPolicy::default(),
+        None,
+    )
+    .unwrap();
+    let message = decode_message(
+        &EncodedMessage {
+            claimed_source: requester,
+            claimed_destination: public_key,
+            received_from: requester,
+            message: seal(
+                &requester_private_key,
+                &public_key,
+                compression_mode,
+                &message,
+            )
+            .unwrap(),
+        },
+        &context.config().private_key,
+        context.config().max_decompressed_message_size,
+        &context.dictionaries,
+    )
+    .unwrap();
+
+    handle_message(&context, requester, message);
+    handle_message(
+        &context,
+        requester,
+        Message::RequestApplicationAdvertisement,
+    );
+
+    let reply = context.message_queue.try_pop().unwrap();
+    assert_eq!(reply.destination, requester);
+    assert!(matches!(reply.message, Message::SystemInformation { .. }));
+
+    let reply = context.message_queue.try_pop().unwrap();
+    assert!(matches!(
+        reply.message,
+        Message::ApplicationAdvertisement { .. }
+    ));
+}
+
+#[test]
+fn routing_table_test() {
+    let (my_public_key, _) = create_keypair();
+    let (near_neighbor, _) = create_keypair();
+    let (far_neighbor, _) = create_keypair();
+    let (destination, _) = create_keypair();
+
+    let sessions = DashMap::new();
+    for neighbor in [near_neighbor, far_neighbor] {
+        sessions.insert(
+            neighbor,
+            ConnectedPeer {
+                packet_sender: channel(1).0,
+                segment_size: MAX_MESSAGE_SEGMENT_SIZE,
+                compression: Arc::new(CompressionPolicy::default()),
+                dictionary: None,
+                max_message_size: usize::MAX,
+                peer: None,
+                disconnect: CancellationToken::new(),
+            },
+        );
+    }
+
+    let routing_table = RoutingTable::default();
+    assert_eq!(routing_table.next_hop(destination, &sessions), None);
+    assert_eq!(
+        routing_table.next_hop(near_neighbor, &sessions),
+        Some(near_neighbor)
+    );
+
+    routing_table.update_routes_via(
+        my_public_key,
+        far_neighbor,
+        &BTreeMap::from([(destination, 3), (my_public_key, 1)]),
+    );
+    routing_table.update_routes_via(
+        my_public_key,
+        near_neighbor,
+        &BTreeMap::from([(destination, 1)]),
+    );
+    assert_eq!(
+        routing_table.next_hop(destination, &sessions),
+        Some(near_neighbor)
+    );
+
+    // Routes learned from a neighbor are never advertised back to it
+    let advertised = routing_table.advertisement_for(near_neighbor, &sessions);
+    assert_eq!(advertised.get(&destination), Some(&4));
+    assert_eq!(advertised.get(&far_neighbor), Some(&1));
+    assert!(!advertised.contains_key(&near_neighbor));
+
+    sessions.remove(&near_neighbor);
+    routing_table.remove_next_hop(near_neighbor);
+    assert_eq!(
+        routing_table.next_hop(destination, &sessions),
+        Some(far_neighbor)
+    );
+}
+
+#[test]
+fn deny_list_test() {
+    let (denied_key, _) = create_keypair();
+    let (allowed_key, private_key) = create_keypair();
+
+    let config: Config = toml::from_str(&format!(
+        r#"
+        public_key = "{allowed_key}"
+        private_key = "{private_key}"
+        deny_list = ["tcp@192.0.2.1", "{denied_key}"]
+        "#
+    ))
+    .unwrap();
+
+    assert!(config.is_denied_peer(&"tcp@192.0.2.1".parse().unwrap()));
+    assert!(config.is_denied_peer(&"tcp@192.0.2.1:5000".parse().unwrap()));
+    assert!(!config.is_denied_peer(&"tcp@192.0.2.2".parse().unwrap()));
+    assert!(config.is_denied_key(&denied_key));
+    assert!(!config.is_denied_key(&allowed_key));
+
+    assert!("not a key".parse::<DenyListEntry>().is_err());
+}
+
+#[tokio::test]
+async fn http_transport_test() {
+    let config = TransportConfig::from([
+        ("bind".to_string(), toml::Value::from("127.0.0.1:0")),
+        ("path_prefix".to_string(), toml::Value::from("/mesh/")),
+    ]);
+    let transport = Arc::new(HttpTransport::new(Some(&config)).await.unwrap());
+
+    let address = Address::Ip("127.0.0.1".parse().unwrap());
+    let (connected, accepted) = tokio::join!(
+        transport.clone().connect(Some(&address)),
+        transport.clone().accept(),
+    );
+    let (mut client_reader, mut client_writer) = connected.unwrap();
+    let ((mut server_reader, mut server_writer), address) = accepted.unwrap();
+    assert_eq!(address, Some(Address::Ip("127.0.0.1".parse().unwrap())));
+
+    let (client_public_key, client_private_key) = create_keypair();
+    let (server_public_key, server_private_key) = create_keypair();
+
+    let (client, server) = tokio::join!(
+        perform_handshake(
+            &mut client_reader,
+            &mut client_writer,
+            &client_private_key,
+            true,
+            &[],
+            u64::MAX
+        ),
+        perform_handshake(
+            &mut server_reader,
+            &mut server_writer,
+            &server_private_key,
+            false,
+            &[],
+            u64::MAX
+        ),
+    );
+    assert_eq!(client.unwrap().0, server_public_key);
+    assert_eq!(server.unwrap().0, client_public_key);
+
+    // Packets much bigger than a single read still make it across in one piece
+    let data = (0..=u8::MAX)
+        .cycle()
+        .take(MAX_MESSAGE_SEGMENT_SIZE)
+        .collect_vec();
+    for packet in segment_message(client_public_key, server_public_key, &data, 4096, false).unwrap()
+    {
+        client_writer.send(packet).await.unwrap();
+    }
+
+    let mut received = Vec::new();
+    while received.len() < data.len() {
+        let packet = server_reader.next().await.unwrap().unwrap();
+        let MessageSegment::Message { data, .. } = packet.message else {
+            panic!("Received the wrong segment type");
+        };
+        received.extend(data.0);
+    }
+    assert_eq!(received, data);
+}
+
+#[tokio::test]
+async fn http_transport_rejects_foreign_paths_test() {
+    let port = std::net::TcpListener::bind("127.0.0.1:0")
+        .unwrap()
+        .local_addr()
+        .unwrap()
+        .port();
+
+    let config = TransportConfig::from([(
+        "bind".to_string(),
+        toml::Value::from(format!("127.0.0.1:{port}")),
+    )]);
+    let _transport = HttpTransport::new(Some(&config)).await.unwrap();
+
+    let mut stream = TcpStream::connect(("127.0.0.1", port)).await.unwrap();
+    stream
+        .write_all(b"GET /index.html HTTP/1.1\r\nHost: localhost\r\n\r\n")
+        .await
+        .unwrap();
+
+    let mut response = String::new();
+    stream.read_to_string(&mut response).await.unwrap();
+    assert!(response.starts_with("HTTP/1.1 404"));
+}
+
+#[tokio::test]
+async fn http_transport_shutdown_test() {
+    let port = std::net::TcpListener::bind("127.0.0.1:0")
+        .unwrap()
+        .local_addr()
+        .unwrap()
+        .port();
+
+    let config = TransportConfig::from([(
+        "bind".to_string(),
+        toml::Value::from(format!("127.0.0.1:{port}")),
+    )]);
+    let transport = HttpTransport::new(Some(&config)).await.unwrap();
+
+    // Half a link waits for its other half well past the shutdown
+    let mut half = TcpStream::connect(("127.0.0.1", port)).await.unwrap();
+    half.write_all(b"GET /routeweaver/abcd/down HTTP/1.1\r\nHost: localhost\r\n\r\n")
+        .await
+        .unwrap();
+    let mut status = [0; 12];
+    half.read_exact(&mut status).await.unwrap();
+    assert_eq!(&status, b"HTTP/1.1 200");
+
+    timeout(Duration::from_secs(1), transport.shutdown())
+        .await
+        .unwrap();
+
+    // Nothing listens anymore, and the half that was waiting got hung up on
+    assert!(TcpStream::connect(("127.0.0.1", port)).await.is_err());
+    let mut rest = Vec::new();
+    half.read_to_end(&mut rest).await.unwrap();
+    HttpTransport::new(Some(&config)).await.unwrap();
+}
+
+#[tokio::test]
+async fn http_transport_config_test() {
+    for (key, value) in [
+        ("bind", toml::Value::from("127.0.0.1")),
+        ("bind", toml::Value::from(3435)),
+        ("path_prefix", toml::Value::from(false)),
+    ] {
+        let config = TransportConfig::from([(key.to_string(), value)]);
+
+        match HttpTransport::new(Some(&config)).await {
+            Err(RouteWeaverError::TransportConfig(message)) => assert!(message.contains(key)),
+            _ => panic!("Invalid {} was accepted", key),
+        }
+    }
+}
+
+#[tokio::test]
+async fn http_last_chunk_test() {
+    let server = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let address = Address::Socket(server.local_addr().unwrap());
+    let config = TransportConfig::from([("bind".to_string(), toml::Value::from("127.0.0.1:0"))]);
+    let transport = Arc::new(HttpTransport::new(Some(&config)).await.unwrap());
+
+    // Stands in for a server, answering the GET and reading the POST body to its end
+    let serve = async {
+        let mut body = Vec::new();
+
+        for _ in 0..2 {
+            let (stream, _) = server.accept().await.unwrap();
+            let mut stream = BufReader::new(stream);
+            let mut request_line = String::new();
+            stream.read_line(&mut request_line).await.unwrap();
+
+            let mut line = String::new();
+            while line != "\r\n" {
+                line.clear();
+                stream.read_line(&mut line).await.unwrap();
+            }
+
+            if request_line.starts_with("GET") {
+                stream
+                    .write_all(b"HTTP/1.1 200 OK\r\nTransfer-Encoding: chunked\r\n\r\n")
+                    .await
+                    .unwrap();
+                // Held open until the link is done with
+                tokio::spawn(async move {
+                    let _ = stream.read_to_end(&mut Vec::new()).await;
+                });
+            } else {
+                stream.read_to_end(&mut body).await.unwrap();
+            }
+        }
+
+        body
+    };
+    let dial = async {
+        let (_, mut writer) = transport.clone().connect(Some(&address)).await.unwrap();
+        writer.close().await.unwrap();
+    };
+
+    let (body, _) = tokio::join!(serve, dial);
+    assert_eq!(body, b"0\r\n\r\n");
+}
+
+/// Bare minimum of an IRC server that registers any free nick and relays channel messages to everyone else
+///
+/// Returns the port it listens on and the longest line it has relayed so far
+async fn start_irc_stand_in() -> (u16, Arc<AtomicUsize>) {
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let port = listener.local_addr().unwrap().port();
+    let clients = Arc::new(DashMap::new());
+    let longest_line = Arc::new(AtomicUsize::new(0));
+
+    let longest_relayed_line = longest_line.clone();
+    tokio::spawn(async move {
+        loop {
+            let (stream, _) = listener.accept().await.unwrap();
+            let (reader, mut writer) = stream.into_split();
+            let (sender, mut receiver) = unbounded_channel::<String>();
+
+            tokio::spawn(async move {
+                while let Some(line) = receiver.recv().await {
+                    writer.write_all(line.as_bytes()).await.unwrap();
+                }
+            });
+
+            let clients = clients.clone();
+            let longest_line = longest_relayed_line.clone();
+            tokio::spawn(async move {
+                let mut lines = BufReader::new(reader).lines();
+                let mut nick = String::new();
+
+                while let Ok(Some(line)) = lines.next_line().await {
+                    let (command, rest) = line.split_once(' ').unwrap_or((&line, ""));
+
+                    match command {
+                        "NICK" if clients.contains_key(rest) => {
+                            sender
+                                .send(format!(
+                                    ":stand-in 433 * {rest} :Nickname is already in use\r\n"
+                                ))
+                                .unwrap();
+                        }
+                        "NICK" => {
+                            nick = rest.to_string();
+                            clients.insert(nick.clone(), sender.clone());
+                        }
+                        "USER" => {
+                            // Make sure pings get answered while registering
+                            sender
+                                .send(":stand-in PING :hello\r\n".to_string())
+                                .unwrap();
+                            sender
+                                .send(format!(":stand-in 001 {nick} :Welcome\r\n"))
+                                .unwrap();
+                        }
+                        "PRIVMSG" => {
+                            let relayed = format!(":{nick}!{nick}@localhost {line}\r\n");
+                            longest_line.fetch_max(relayed.len(), Ordering::Relaxed);
+
+                            for client in clients.iter() {
+                                if *client.key() != nick {
+                                    let _ = client.value().send(relayed.clone());
+                                }
+                            }
+                        }
+                        "QUIT" => break,
+                        _ => {}
+                    }
+                }
+
+                // Hangs up on the client once its writer runs out of senders
+                clients.remove(&nick);
+            });
+        }
+    });
+
+    (port, longest_line)
+}
+
+#[tokio::test]
+async fn irc_transport_test() {
+    let (port, longest_line) = start_irc_stand_in().await;
+
+    let irc_config = |nick: &str| {
+        TransportConfig::from([
+            (
+                "server".to_string(),
+                toml::Value::from(format!("127.0.0.1:{port}")),
+            ),
+            ("nick".to_string(), toml::Value::from(nick)),
+        ])
+    };
+
+    let alice = Arc::new(IrcTransport::new(Some(&irc_config("alice"))).await.unwrap());
+    let bob = Arc::new(IrcTransport::new(Some(&irc_config("bob"))).await.unwrap());
+
+    let (mut alice_reader, mut alice_writer) = alice
+        .clone()
+        .connect(Some(&Address::Nick("bob".to_string())))
+        .await
+        .unwrap();
+    let ((mut bob_reader, mut bob_writer), address) = bob.clone().accept().await.unwrap();
+    assert_eq!(address, Some(Address::Nick("alice".to_string())));
+
+    let (alice_public_key, alice_private_key) = create_keypair();
+    let (bob_public_key, bob_private_key) = create_keypair();
+
+    let (alice_session, bob_session) = tokio::join!(
+        perform_handshake(
+            &mut alice_reader,
+            &mut alice_writer,
+            &alice_private_key,
+            true,
+            &[],
+            u64::MAX
+        ),
+        perform_handshake(
+            &mut bob_reader,
+            &mut bob_writer,
+            &bob_private_key,
+            false,
+            &[],
+            u64::MAX
+        ),
+    );
+    assert_eq!(alice_session.unwrap().0, bob_public_key);
+    assert_eq!(bob_session.unwrap().0, alice_public_key);
+
+    let segment_size = alice.recommended_message_segment_size().unwrap();
+    let data = (0..=u8::MAX).cycle().take(segment_size).collect_vec();
+    for packet in
+        segment_message(alice_public_key, bob_public_key, &data, segment_size, false).unwrap()
+    {
+        alice_writer.send(packet).await.unwrap();
+    }
+
+    let packet = bob_reader.next().await.unwrap().unwrap();
+    let MessageSegment::Message { data: received, .. } = packet.message else {
+        panic!("Received the wrong segment type");
+    };
+    assert_eq!(received.0, data);
+
+    assert!(longest_line.load(Ordering::Relaxed) <= 512);
+}
+
+#[tokio::test]
+async fn irc_transport_config_test() {
+    for (key, value) in [
+        ("segment_size", toml::Value::from(0)),
+        ("segment_size", toml::Value::from(-1)),
+        (
+            "segment_size",
+            toml::Value::from(MAX_MESSAGE_SEGMENT_SIZE as i64 + 1),
+        ),
+        ("line_interval_ms", toml::Value::from(-1)),
+    ] {
+        // Nothing listens here, so a setting that slips through fails to connect instead
+        let config = TransportConfig::from([
+            ("server".to_string(), toml::Value::from("127.0.0.1:1")),
+            (key.to_string(), value),
+        ]);
+
+        match IrcTransport::new(Some(&config)).await {
+            Err(RouteWeaverError::TransportConfig(message)) => assert!(message.contains(key)),
+            _ => panic!("Invalid {} was accepted", key),
+        }
+    }
+}
+
+#[tokio::test]
+async fn irc_transport_shutdown_test() {
+    let (port, _) = start_irc_stand_in().await;
+    let config = TransportConfig::from([
+        (
+            "server".to_string(),
+            toml::Value::from(format!("127.0.0.1:{port}")),
+        ),
+        ("nick".to_string(), toml::Value::from("carol")),
+    ]);
+
+    let transport = IrcTransport::new(Some(&config)).await.unwrap();
+    assert!(IrcTransport::new(Some(&config)).await.is_err());
+
+    // Quitting frees the nick by the time shutdown returns
+    timeout(Duration::from_secs(1), transport.shutdown())
+        .await
+        .unwrap();
+    IrcTransport::new(Some(&config)).await.unwrap();
+}
+
+#[tokio::test]
+async fn irc_backed_up_link_test() {
+    let (port, _) = start_irc_stand_in().await;
+    let irc_config = |nick: &str| {
+        TransportConfig::from([
+            (
+                "server".to_string(),
+                toml::Value::from(format!("127.0.0.1:{port}")),
+            ),
+            ("nick".to_string(), toml::Value::from(nick)),
+        ])
+    };
+
+    let alice = Arc::new(IrcTransport::new(Some(&irc_config("alice"))).await.unwrap());
+    let bob = Arc::new(IrcTransport::new(Some(&irc_config("bob"))).await.unwrap());
+    let carol = Arc::new(IrcTransport::new(Some(&irc_config("carol"))).await.unwrap());
+
+    let (_, mut alice_writer) = alice
+        .clone()
+        .connect(Some(&Address::Nick("bob".to_string())))
+        .await
+        .unwrap();
+    // Never read from, so its queue fills up
+    let (_bob_reader, _) = bob.clone().accept().await.unwrap();
+
+    let (source, _) = create_keypair();
+    let (destination, _) = create_keypair();
+    let packets = segment_message(source, destination, &[0; 2048], 1, false).unwrap();
+    for packet in packets {
+        alice_writer.feed(packet).await.unwrap();
+    }
+    alice_writer.flush().await.unwrap();
+
+    // Bob keeps reading the server, so other links still come through
+    carol
+        .clone()
+        .connect(Some(&Address::Nick("bob".to_string())))
+        .await
+        .unwrap();
+    let (_, address) = timeout(Duration::from_secs(5), bob.clone().accept())
+        .await
+        .unwrap()
+        .unwrap();
+    assert_eq!(address, Some(Address::Nick("carol".to_string())));
+}
+
+#[test]
+fn config_validation_test() {
+    let (public_key, private_key) = create_keypair();
+    let (other_key, _) = create_keypair();
+
+    let load = |extra: &str| {
+        toml::from_str::<Config>(&format!("private_key = \"{private_key}\"\n{extra}"))
+    };
+
+    // The public key is worked out from the private one when left out
+    assert_eq!(load("").unwrap().public_key, public_key);
+    // Logging the config doesn't leak the key
+    assert!(!format!("{:?}", load("").unwrap()).contains(&private_key.to_string()));
+    assert!(load(&format!(
+        "public_key = \"{public_key}\"\nenabled_transports = [\"tcp\"]"
+    ))
+    .unwrap()
+    .validate()
+    .is_ok());
+    let error = load(&format!("public_key = \"{other_key}\""))
+        .unwrap()
+        .validate()
+        .unwrap_err();
+    assert!(matches!(error, RouteWeaverError::KeyMismatch));
+
+    assert!(load("").unwrap().validate().is_err());
+    assert!(load(r#"enabled_transports = ["tcp"]"#)
+        .unwrap()
+        .validate()
+        .is_ok());
+    assert!(load(r#"enabled_transports = ["bluetooth"]"#)
+        .unwrap()
+        .validate()
+        .is_err());
+    assert!(load(
+        r#"
+        enabled_transports = ["tcp"]
+        seeders = ["unix@/run/routeweaver.sock"]
+        "#
+    )
+    .unwrap()
+    .validate()
+    .is_err());
+    assert!(load(
+        r#"
+        enabled_transports = ["tcp"]
+        seeders = ["tcp@192.0.2.1"]
+        deny_list = ["tcp@192.0.2.1"]
+        "#
+    )
+    .unwrap()
+    .validate()
+    .is_err());
+    assert!(load(
+        r#"
+        enabled_transports = ["tcp"]
+        transport_configs.tcp.compression = ["brotli"]
+        "#
+    )
+    .unwrap()
+    .validate()
+    .is_err());
+    assert!(load(&format!(
+        r#"
+        enabled_transports = ["tcp"]
+        max_decompressed_message_size = {}
+        "#,
+        MAX_DECOMPRESSED_MESSAGE_SIZE + 1
+    ))
+    .unwrap()
+    .validate()
+    .is_err());
+    assert!(load(
+        r#"
+        enabled_transports = ["tcp"]
+        zstd_dictionaries = ["/nonexistent/routeweaver.dict"]
+        "#
+    )
+    .unwrap()
+    .validate()
+    .is_err());
+}
+
+#[test]
+fn private_key_source_test() {
+    let directory = std::env::temp_dir().join(format!(
+        "routeweaver-test-key-source-{}",
+        std::process::id()
+    ));
+    let key_path = directory.join("key");
+    std::fs::create_dir_all(&directory).unwrap();
+
+    let public_key = identity::keygen(&key_path, false).unwrap();
+    let from_file = format!("private_key_file = {:?}", key_path);
+    assert_eq!(
+        toml::from_str::<Config>(&from_file).unwrap().public_key,
+        public_key
+    );
+
+    let variable = format!("ROUTEWEAVER_TEST_KEY_{}", std::process::id());
+    std::env::set_var(&variable, std::fs::read_to_string(&key_path).unwrap());
+    let from_variable = format!("private_key_env = {:?}", variable);
+    assert_eq!(
+        toml::from_str::<Config>(&from_variable).unwrap().public_key,
+        public_key
+    );
+    std::env::remove_var(&variable);
+    assert!(toml::from_str::<Config>(&from_variable).is_err());
+
+    // Two sources would leave it ambiguous which identity we are
+    let (_, private_key) = create_keypair();
+    assert!(
+        toml::from_str::<Config>(&format!("private_key = \"{private_key}\"\n{from_file}")).is_err()
+    );
+
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::PermissionsExt;
+
+        std::fs::set_permissions(&key_path, std::fs::Permissions::from_mode(0o644)).unwrap();
+        let error = toml::from_str::<Config>(&from_file).unwrap_err();
+        assert!(error
+            .to_string()
+            .contains("only be accessible by its owner"));
+    }
+
+    std::fs::remove_dir_all(&directory).unwrap();
+}
+
+#[test]
+fn identity_test() {
+    let directory =
+        std::env::temp_dir().join(format!("routeweaver-test-identity-{}", std::process::id()));
+    let key_path = directory.join("key");
+    let config_path = directory.join("config.toml");
+    std::fs::create_dir_all(&directory).unwrap();
+
+    let public_key = identity::keygen(&key_path, false).unwrap();
+    assert_eq!(identity::pubkey(Some(&key_path)).unwrap(), public_key);
+    // An existing identity is never silently replaced
+    assert!(identity::keygen(&key_path, false).is_err());
+    assert_ne!(identity::keygen(&key_path, true).unwrap(), public_key);
+
+    let public_key = identity::init(&config_path, false).unwrap();
+    let config: Config = toml::from_str(&std::fs::read_to_string(&config_path).unwrap()).unwrap();
+    assert_eq!(config.public_key, public_key);
+    assert_eq!(derive_public_key(&config.private_key), public_key);
+
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::PermissionsExt;
+
+        for path in [&key_path, &config_path] {
+            let mode = std::fs::metadata(path).unwrap().permissions().mode();
+            assert_eq!(mode & 0o777, 0o600);
+        }
+    }
+
+    std::fs::remove_dir_all(&directory).unwrap();
+}
+
+#[test]
+fn peer_address_test() {
+    let peer: Peer = "tcp@1.2.3.4:5000".parse().unwrap();
+    assert_eq!(
+        peer.address,
+        Address::Socket("1.2.3.4:5000".parse().unwrap())
+    );
+    assert_eq!(peer.to_string(), "tcp@1.2.3.4:5000");
+
+    let peer: Peer = "tcp@[::1]:5000".parse().unwrap();
+    assert_eq!(peer.to_string(), "tcp@[::1]:5000");
+
+    let peer: Peer = "tcp@::1".parse().unwrap();
+    assert_eq!(peer.address, Address::Ip("::1".parse().unwrap()));
+
+    assert!("tcp@1.2.3.4:99999".parse::<Peer>().is_err());
+}
+
+#[tokio::test]
+async fn tcp_transport_test() {
+    let config = TransportConfig::from([
+        ("bind".to_string(), toml::Value::from("127.0.0.1")),
+        ("port".to_string(), toml::Value::from(0)),
+        ("backlog".to_string(), toml::Value::from(16)),
+    ]);
+    let transport = Arc::new(TcpTransport::new(Some(&config)).await.unwrap());
+
+    // The port we were given is used for peers that don't name one
+    let address = Address::Ip("127.0.0.1".parse().unwrap());
+    let (connected, accepted) = tokio::join!(
+        transport.clone().connect(Some(&address)),
+        transport.clone().accept(),
+    );
+    let (_, mut client_writer) = connected.unwrap();
+    let ((mut server_reader, _), address) = accepted.unwrap();
+    assert_eq!(address, Some(Address::Ip("127.0.0.1".parse().unwrap())));
+
+    let (source, _) = create_keypair();
+    let (destination, _) = create_keypair();
+
+    for packet in segment_message(source, destination, b"hello", 64, false).unwrap() {
+        client_writer.send(packet).await.unwrap();
+    }
+
+    let packet = server_reader.next().await.unwrap().unwrap();
+    assert_eq!(packet.source, source);
+    assert_eq!(packet.destination, destination);
+}
+
+#[tokio::test]
+async fn tcp_transport_config_test() {
+    for (key, value) in [
+        ("bind", toml::Value::from("localhost")),
+        ("bind", toml::Value::from(1)),
+        ("port", toml::Value::from(65536)),
+        ("port", toml::Value::from("3434")),
+        ("backlog", toml::Value::from(i64::from(i32::MAX) + 1)),
+        ("backlog", toml::Value::from("many")),
+        ("ipv6_only", toml::Value::from("yes")),
+    ] {
+        let config = TransportConfig::from([(key.to_string(), value)]);
+
+        // Refused before anything gets bound, naming what is wrong
+        match TcpTransport::new(Some(&config)).await {
+            Err(RouteWeaverError::TransportConfig(message)) => assert!(message.contains(key)),
+            _ => panic!("Invalid {} was accepted", key),
+        }
+    }
+}
+
+#[tokio::test]
+async fn unix_transport_test() {
+    let unix_config = |name: &str| {
+        let path = std::env::temp_dir().join(format!(
+            "routeweaver-test-{}-{}.sock",
+            name,
+            std::process::id()
+        ));
+
+        (
+            path.clone(),
+            TransportConfig::from([(
+                "socket_path".to_string(),
+                toml::Value::from(path.to_str().unwrap()),
+            )]),
+        )
+    };
+
+    let (_, node_a_config) = unix_config("node-a");
+    let (node_b_path, node_b_config) = unix_config("node-b");
+
+    let node_a = Arc::new(UnixTransport::new(Some(&node_a_config)).await.unwrap());
+    let node_b = Arc::new(UnixTransport::new(Some(&node_b_config)).await.unwrap());
+
+    let peer: Peer = format!("unix@{}", node_b_path.display()).parse().unwrap();
+    assert_eq!(peer.address, Address::Path(node_b_path));
+
+    let (connected, accepted) = tokio::join!(
+        node_a.clone().connect(Some(&peer.address)),
+        node_b.clone().accept(),
+    );
+    let (_, mut node_a_writer) = connected.unwrap();
+    let ((mut node_b_reader, _), _) = accepted.unwrap();
+
+    let (source, _) = create_keypair();
+    let (destination, _) = create_keypair();
+
+    for packet in segment_message(source, destination, b"hello", 64, false).unwrap() {
+        node_a_writer.send(packet).await.unwrap();
+    }
+
+    let packet = node_b_reader.next().await.unwrap().unwrap();
+    assert_eq!(packet.source, source);
+}
+
+/// A socket at a fresh path that hangs up on whoever dials it, noting when they did
+fn spawn_hanging_up_listener(name: &str) -> (Address, Receiver<Instant>) {
+    let path = std::env::temp_dir().join(format!(
+        "routeweaver-test-{}-{}.sock",
+        name,
+        std::process::id()
+    ));
+    let _ = std::fs::remove_file(&path);
+    let listener = UnixListener::bind(&path).unwrap();
+    let (dialed_sender, dialed) = channel(1024);
+
+    tokio::spawn(async move {
+        // Dropping the stream fails the handshake right away
+        while listener.accept().await.is_ok() {
+            if dialed_sender.send(Instant::now()).await.is_err() {
+                break;
+            }
+        }
+    });
+
+    (Address::Path(path), dialed)
+}
+
+#[tokio::test]
+async fn redial_backoff_test() {
+    let (_, private_key) = create_keypair();
+    let (address, mut dialed) = spawn_hanging_up_listener("backoff");
+
+    let config: Config = toml::from_str(&format!(r#"private_key = "{private_key}""#)).unwrap();
+    let transport = Arc::new(UnixTransport::new(None).await.unwrap());
+    let stop = CancellationToken::new();
+    let dialer = tokio::spawn(maintain_connection_to_peer(
+        transport,
+        address,
+        create_context(config),
+        stop.clone(),
+    ));
+
+    let mut dials = Vec::new();
+    for _ in 0..3 {
+        dials.push(
+            timeout(Duration::from_secs(5), dialed.recv())
+                .await
+                .unwrap()
+                .unwrap(),
+        );
+    }
+
+    // Failed handshakes keep doubling the delay rather than starting over
+    let first_delay = dials[1] - dials[0];
+    let second_delay = dials[2] - dials[1];
+    assert!(first_delay >= Duration::from_millis(900));
+    assert!(second_delay >= first_delay + Duration::from_millis(500));
+
+    // Stopping interrupts the wait for the next dial
+    stop.cancel();
+    timeout(Duration::from_millis(100), dialer)
+        .await
+        .unwrap()
+        .unwrap();
+    assert!(dialed.try_recv().is_err());
+}
+
+#[tokio::test]
+async fn dial_seeders_test() {
+    let (_, private_key) = create_keypair();
+    let (Address::Path(path), mut dialed) = spawn_hanging_up_listener("seeder") else {
+        unreachable!()
+    };
+
+    let config = |seeders: &str| -> Config {
+        toml::from_str(&format!(
+            r#"
+            private_key = "{private_key}"
+            enabled_transports = ["unix"]
+            seeders = [{seeders}]
+            "#
+        ))
+        .unwrap()
+    };
+    let context = create_context(config(&format!(r#""unix@{}""#, path.display())));
+    let transport = Arc::new(UnixTransport::new(None).await.unwrap());
+    context
+        .tasks
+        .spawn(dial_seeders(transport, context.clone()));
+
+    timeout(Duration::from_secs(1), dialed.recv())
+        .await
+        .unwrap()
+        .unwrap();
+    assert_eq!(context.tasks.len(), 2);
+
+    // Dropping the seeder from the config stops its dialer
+    context.config.send_replace(Arc::new(config("")));
+    timeout(Duration::from_secs(1), async {
+        while context.tasks.len() > 1 {
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+    })
+    .await
+    .unwrap();
+
+    context.shutdown.cancel();
+    context.tasks.close();
+    timeout(Duration::from_secs(1), context.tasks.wait())
+        .await
+        .unwrap();
+}
+
+#[tokio::test]
+async fn shutdown_test() {
+    let (public_key, private_key) = create_keypair();
+    let (_, client_private_key) = create_keypair();
+    let client_private_key = Arc::new(client_private_key);
+    let socket_path = std::env::temp_dir().join(format!(
+        "routeweaver-test-shutdown-{}.sock",
+        std::process::id()
+    ));
+
+    let config: Config = toml::from_str(&format!(
+        r#"
+        public_key = "{public_key}"
+        private_key = "{private_key}"
+        enabled_transports = ["unix"]
+
+        [transport_configs.unix]
+        socket_path = "{}"
+        "#,
+        socket_path.display()
+    ))
+    .unwrap();
+    let context = create_context(config);
+    context
+        .tasks
+        .spawn(start_transport::<UnixTransport>(context.clone()));
+
+    let (mut complete_message_receiver, listener) =
+        connect_unix_client(&context, &socket_path, client_private_key.clone()).await;
+
+    // Returns well before the goodbye would time out, as the client acknowledges it
+    timeout(Duration::from_secs(2), shutdown(&context))
+        .await
+        .unwrap();
+
+    // The goodbye gets flushed before the connection is closed behind it
+    let message = timeout(Duration::from_secs(1), complete_message_receiver.recv())
+        .await
+        .unwrap()
+        .unwrap();
+    assert_eq!(message.claimed_source, public_key);
+    assert!(matches!(
+        decode_message(
+            &message,
+            &client_private_key,
+            usize::MAX,
+            &ZstdDictionaries::default()
+        )
+        .unwrap(),
+        Message::Goodbye
+    ));
+    timeout(Duration::from_secs(1), listener)
+        .await
+        .unwrap()
+        .unwrap();
+
+    assert!(context.tasks.is_empty());
+    assert!(!socket_path.exists());
+}
+
+#[tokio::test]
+async fn config_reload_test() {
+    let (_, private_key) = create_keypair();
+    let (client_public_key, client_private_key) = create_keypair();
+    let client_private_key = Arc::new(client_private_key);
+    let (_, other_private_key) = create_keypair();
+
+    let directory =
+        std::env::temp_dir().join(format!("routeweaver-test-reload-{}", std::process::id()));
+    std::fs::create_dir_all(&directory).unwrap();
+    let config_location = directory.join("config.toml");
+    let socket_path = directory.join("node.sock");
+
+    let write_config = |private_key: &PrivateKey, settings: &str| {
+        std::fs::write(
+            &config_location,
+            format!(
+                r#"
+                private_key = "{private_key}"
+                {settings}
+
+                [transport_configs.unix]
+                socket_path = "{}"
+                "#,
+                socket_path.display()
+            ),
+        )
+        .unwrap()
+    };
+
+    write_config(&private_key, r#"enabled_transports = ["unix"]"#);
+    let context = create_context(Config::load(&config_location).unwrap());
+    context.tasks.spawn(manage_transports(context.clone()));
+
+    let (mut complete_message_receiver, listener) =
+        connect_unix_client(&context, &socket_path, client_private_key.clone()).await;
+
+    // Broken configs and new identities are refused without touching what is running
+    write_config(&private_key, "enabled_transports = []");
+    assert!(reload_config(&context, &config_location).await.is_err());
+    write_config(&other_private_key, r#"enabled_transports = ["unix"]"#);
+    assert!(reload_config(&context, &config_location).await.is_err());
+    assert!(context
+        .config()
+        .enabled_transports
+        .contains(&Protocol::Unix));
+    assert!(context.session_tracker.contains_key(&client_public_key));
+
+    write_config(
+        &private_key,
+        &format!(
+            r#"
+            enabled_transports = ["unix"]
+            deny_list = ["{client_public_key}"]
+            "#
+        ),
+    );
+    reload_config(&context, &config_location).await.unwrap();
+
+    // Newly denied peers are told so before being hung up on
+    let message = timeout(Duration::from_secs(1), complete_message_receiver.recv())
+        .await
+        .unwrap()
+        .unwrap();
+    assert!(matches!(
+        decode_message(
+            &message,
+            &client_private_key,
+            usize::MAX,
+            &ZstdDictionaries::default()
+        )
+        .unwrap(),
+        Message::Denied
+    ));
+    timeout(Duration::from_secs(1), listener)
+        .await
+        .unwrap()
+        .unwrap();
+
+    write_config(
+        &private_key,
+        r#"
+        enabled_transports = ["tcp"]
+
+        [transport_configs.tcp]
+        bind = "127.0.0.1"
+        port = 0
+        "#,
+    );
+    reload_config(&context, &config_location).await.unwrap();
+
+    // Disabling a transport takes its socket with it
+    timeout(Duration::from_secs(5), async {
+        while socket_path.exists() {
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+    })
+    .await
+    .unwrap();
+
+    shutdown(&context).await;
+    std::fs::remove_dir_all(&directory).unwrap();
+}
+
+#[tokio::test]
+async fn transport_restart_test() {
+    let (_, private_key) = create_keypair();
+    let port = std::net::TcpListener::bind("127.0.0.1:0")
+        .unwrap()
+        .local_addr()
+        .unwrap()
+        .port();
+
+    let config_location = std::env::temp_dir().join(format!(
+        "routeweaver-test-restart-{}.toml",
+        std::process::id()
+    ));
+    let write_config = |path_prefix: &str| {
+        std::fs::write(
+            &config_location,
+            format!(
+                r#"
+                private_key = "{private_key}"
+                enabled_transports = ["http"]
+
+                [transport_configs.http]
+                bind = "127.0.0.1:{port}"
+                path_prefix = "{path_prefix}"
+                "#
+            ),
+        )
+        .unwrap()
+    };
+
+    // Which of the two transports answers shows through the path it accepts links on
+    let answers_on = |path_prefix: &'static str| async move {
+        let Ok(mut stream) = TcpStream::connect(("127.0.0.1", port)).await else {
+            return false;
+        };
+        let request = format!("GET {path_prefix}/abcd/down HTTP/1.1\r\nHost: localhost\r\n\r\n");
+        let mut status = [0; 12];
+
+        stream.write_all(request.as_bytes()).await.is_ok()
+            && stream.read_exact(&mut status).await.is_ok()
+            && &status == b"HTTP/1.1 200"
+    };
+    let wait_for = |path_prefix| async move {
+        timeout(Duration::from_secs(5), async {
+            while !answers_on(path_prefix).await {
+                tokio::time::sleep(Duration::from_millis(10)).await;
+            }
+        })
+        .await
+        .unwrap()
+    };
+
+    write_config("/old");
+    let context = create_context(Config::load(&config_location).unwrap());
+    context.tasks.spawn(manage_transports(context.clone()));
+    wait_for("/old").await;
+
+    // The new transport binds the same address the old one has to let go of first
+    write_config("/new");
+    reload_config(&context, &config_location).await.unwrap();
+    wait_for("/new").await;
+    assert!(!answers_on("/old").await);
+
+    shutdown(&context).await;
+    std::fs::remove_file(&config_location).unwrap();
+}
+
+#[tokio::test]
+async fn admin_socket_test() {
+    use std::os::unix::fs::PermissionsExt;
+
+    let (_, private_key) = create_keypair();
+    let config: Config = toml::from_str(&format!(r#"private_key = "{private_key}""#)).unwrap();
+    let context = create_context(config);
+    let path = std::env::temp_dir().join(format!("routeweaver-test-admin-{}", std::process::id()));
+
+    context.tasks.spawn(serve_admin_socket(
+        context.clone(),
+        path.clone(),
+        path.with_extension("toml"),
+    ));
+
+    timeout(Duration::from_secs(5), async {
+        while !path.exists() {
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+    })
+    .await
+    .unwrap();
+    assert_eq!(
+        std::fs::metadata(&path).unwrap().permissions().mode() & 0o777,
+        0o600
+    );
+
+    // A client that never sends anything only holds the others up for so long
+    let _idle = tokio::net::UnixStream::connect(&path).await.unwrap();
+    let response = timeout(Duration::from_secs(10), send_admin_command(&path, "status"))
+        .await
+        .unwrap();
+    assert!(
+        matches!(response, Err(RouteWeaverError::Admin(message)) if message.contains("status"))
+    );
 
-}
\ No newline at end of file
+    shutdown(&context).await;
+    assert!(!path.exists());
+}
@@ -0,0 +1,491 @@
+use super::{
+    Connection, PacketEncoderDecoder, Transport, TransportReader, TransportWriter, MAX_FRAME_SIZE,
+};
+use crate::{
+    config::TransportConfig,
+    error::RouteWeaverError,
+    proto::{Address, Packet, Protocol},
+};
+use bytes::{Buf, BufMut, BytesMut};
+use dashmap::DashMap;
+use data_encoding::HEXLOWER;
+use deadqueue::unlimited::Queue;
+use futures_util::{Sink, Stream};
+use std::{
+    net::{IpAddr, SocketAddr},
+    pin::{pin, Pin},
+    sync::Arc,
+    time::Duration,
+};
+use tokio::{
+    io::{AsyncBufReadExt, AsyncWriteExt, BufReader},
+    net::{TcpListener, TcpStream},
+    time::{sleep, timeout},
+};
+use tokio_util::{
+    codec::{Decoder, Encoder, FramedRead, FramedWrite},
+    sync::CancellationToken,
+    task::TaskTracker,
+};
+
+const DEFAULT_BIND_ADDRESS: &str = "[::]:3435";
+const DEFAULT_PATH_PREFIX: &str = "/routeweaver";
+// Generous enough for any proxy that might decorate our requests
+const MAX_HEAD_SIZE: usize = 8192;
+const HEAD_TIMEOUT: Duration = Duration::from_secs(10);
+// How long the first half of a link waits for its other half to show up
+const PENDING_HALF_TIMEOUT: Duration = Duration::from_secs(30);
+// Halves waiting on their other half at once, past which new ones are hung up on
+const MAX_PENDING_HALVES: usize = 256;
+// Ends a chunked body, so whoever sits in between can tell it apart from a cut off one
+const LAST_CHUNK: &[u8] = b"0\r\n\r\n";
+
+type HttpStream = BufReader<TcpStream>;
+type PendingHalves = Arc<DashMap<String, (Direction, HttpStream)>>;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Direction {
+    // Client to server, carried in a chunked POST body
+    Up,
+    // Server to client, carried in a chunked GET response
+    Down,
+}
+
+/// Carries packets over a pair of long lived HTTP/1.1 requests
+///
+/// Each link is made of a POST whose chunked body carries packets towards the server and a GET whose
+/// chunked response carries them back, tied together by a random link id in the path. Keeping the two
+/// directions on separate requests means proxies that only forward one direction at a time still work.
+pub struct HttpTransport {
+    port: u16,
+    path_prefix: String,
+    accepted: Arc<Queue<(Connection<Self>, Option<Address>)>>,
+    // The listener and every request still being read belong to these
+    stop: CancellationToken,
+    tasks: TaskTracker,
+}
+
+impl Transport for HttpTransport {
+    const PROTOCOL: Protocol = Protocol::Http;
+
+    type Reader = HttpPacketReader;
+    type Writer = HttpPacketWriter;
+
+    async fn new(config: Option<&TransportConfig>) -> Result<Self, RouteWeaverError>
+    where
+        Self: Sized,
+    {
+        let invalid = |key| {
+            RouteWeaverError::TransportConfig(format!("{} has an invalid {}", Self::PROTOCOL, key))
+        };
+
+        let bind_address: SocketAddr = match config.and_then(|config| config.get("bind")) {
+            Some(value) => value
+                .as_str()
+                .and_then(|address| address.parse().ok())
+                .ok_or_else(|| invalid("bind"))?,
+            None => DEFAULT_BIND_ADDRESS.parse().unwrap(),
+        };
+
+        let path_prefix = match config.and_then(|config| config.get("path_prefix")) {
+            Some(value) => value.as_str().ok_or_else(|| invalid("path_prefix"))?,
+            None => DEFAULT_PATH_PREFIX,
+        }
+        .trim_end_matches('/')
+        .to_string();
+
+        let listener = TcpListener::bind(bind_address).await?;
+        let accepted = Arc::new(Queue::new());
+
+        let port = listener.local_addr()?.port();
+        let stop = CancellationToken::new();
+        let tasks = TaskTracker::new();
+
+        tasks.spawn(accept_http_requests(
+            listener,
+            path_prefix.clone(),
+            accepted.clone(),
+            stop.clone(),
+            tasks.clone(),
+        ));
+
+        Ok(Self {
+            port,
+            path_prefix,
+            accepted,
+            stop,
+            tasks,
+        })
+    }
+
+    async fn connect(
+        self: Arc<Self>,
+        address: Option<&Address>,
+    ) -> Result<Connection<Self>, RouteWeaverError> {
+        let address = match address {
+            Some(Address::Ip(ip_addr)) => SocketAddr::new(*ip_addr, self.port),
+            Some(Address::Socket(socket_addr)) => *socket_addr,
+            _ => return Err(RouteWeaverError::PeerAddress),
+        };
+        let link_id = HEXLOWER.encode(&rand::random::<[u8; 16]>());
+
+        let mut down = BufReader::new(TcpStream::connect(address).await?);
+        let mut up = BufReader::new(TcpStream::connect(address).await?);
+
+        down.write_all(
+            format!(
+                "GET {}/{}/down HTTP/1.1\r\nHost: {}\r\nAccept: application/octet-stream\r\n\r\n",
+                self.path_prefix, link_id, address
+            )
+            .as_bytes(),
+        )
+        .await?;
+
+        up.write_all(
+            format!(
+                "POST {}/{}/up HTTP/1.1\r\nHost: {}\r\nContent-Type: application/octet-stream\r\nTransfer-Encoding: chunked\r\n\r\n",
+                self.path_prefix, link_id, address
+            )
+            .as_bytes(),
+        )
+        .await?;
+
+        let status_line = timeout(HEAD_TIMEOUT, read_head(&mut down))
+            .await
+            .map_err(|_| RouteWeaverError::TransportConnection)??
+            .into_iter()
+            .next()
+            .unwrap_or_default();
+
+        if status_line.split_whitespace().nth(1) != Some("200") {
+            log::error!("HTTP peer at {} refused our link: {}", address, status_line);
+            return Err(RouteWeaverError::TransportConnection);
+        }
+
+        Ok((HttpPacketReader::new(down), HttpPacketWriter::new(up)))
+    }
+
+    async fn accept(
+        self: Arc<Self>,
+    ) -> Result<(Connection<Self>, Option<Address>), RouteWeaverError> {
+        Ok(self.accepted.pop().await)
+    }
+
+    async fn shutdown(&self) {
+        // The listener goes with the task accepting on it
+        self.stop.cancel();
+        self.tasks.close();
+        self.tasks.wait().await;
+    }
+}
+
+async fn accept_http_requests(
+    listener: TcpListener,
+    path_prefix: String,
+    accepted: Arc<Queue<(Connection<HttpTransport>, Option<Address>)>>,
+    stop: CancellationToken,
+    tasks: TaskTracker,
+) {
+    let pending_halves = PendingHalves::default();
+
+    loop {
+        let (stream, address) = tokio::select! {
+            connection = listener.accept() => match connection {
+                Ok(connection) => connection,
+                Err(e) => {
+                    log::error!("Failed to accept HTTP connection: {}", e);
+                    continue;
+                }
+            },
+            _ = stop.cancelled() => break,
+        };
+
+        let address = Address::Ip(match address.ip() {
+            IpAddr::V4(ip) => IpAddr::V4(ip),
+            IpAddr::V6(ip) => ip
+                .to_ipv4_mapped()
+                .map_or_else(|| IpAddr::V6(ip), IpAddr::V4),
+        });
+
+        let request = handle_http_request(
+            BufReader::new(stream),
+            address,
+            path_prefix.clone(),
+            pending_halves.clone(),
+            accepted.clone(),
+        );
+        let stop = stop.clone();
+
+        tasks.spawn(async move {
+            tokio::select! {
+                _ = request => {}
+                _ = stop.cancelled() => {}
+            }
+        });
+    }
+}
+
+/// Pair up the two halves of a link, handing it over once both have arrived
+async fn handle_http_request(
+    mut stream: HttpStream,
+    address: Address,
+    path_prefix: String,
+    pending_halves: PendingHalves,
+    accepted: Arc<Queue<(Connection<HttpTransport>, Option<Address>)>>,
+) {
+    let (link_id, direction) =
+        match timeout(HEAD_TIMEOUT, read_request(&mut stream, &path_prefix)).await {
+            Ok(Ok(request)) => request,
+            _ => {
+                let _ = stream
+                    .write_all(b"HTTP/1.1 404 Not Found\r\nContent-Length: 0\r\n\r\n")
+                    .await;
+                return;
+            }
+        };
+
+    if direction == Direction::Down {
+        let response = b"HTTP/1.1 200 OK\r\nContent-Type: application/octet-stream\r\nTransfer-Encoding: chunked\r\nCache-Control: no-store\r\n\r\n";
+
+        if stream.write_all(response).await.is_err() {
+            return;
+        }
+    }
+
+    let other_half = pending_halves.remove(&link_id);
+
+    match (direction, other_half) {
+        (Direction::Up, Some((_, (Direction::Down, down))))
+        | (Direction::Down, Some((_, (Direction::Up, down)))) => {
+            // Whichever way around they arrived, figure out which stream is which
+            let (up, down) = if direction == Direction::Up {
+                (stream, down)
+            } else {
+                (down, stream)
+            };
+
+            accepted.push((
+                (HttpPacketReader::new(up), HttpPacketWriter::new(down)),
+                Some(address),
+            ));
+        }
+        (_, Some(_)) => {
+            log::warn!("Received the same half of HTTP link {} twice", link_id);
+        }
+        (_, None) => {
+            if pending_halves.len() >= MAX_PENDING_HALVES {
+                log::warn!("Too many HTTP links are half open, dropping {}", link_id);
+                return;
+            }
+
+            pending_halves.insert(link_id.clone(), (direction, stream));
+
+            sleep(PENDING_HALF_TIMEOUT).await;
+            if pending_halves.remove(&link_id).is_some() {
+                log::warn!("HTTP link {} never completed", link_id);
+            }
+        }
+    }
+}
+
+/// Read everything up to the blank line that ends a request or response head
+async fn read_head(stream: &mut HttpStream) -> Result<Vec<String>, RouteWeaverError> {
+    let mut lines = Vec::new();
+    let mut total_length = 0;
+
+    loop {
+        let mut line = String::new();
+        let length = stream.read_line(&mut line).await?;
+        total_length += length;
+
+        if length == 0 || total_length > MAX_HEAD_SIZE {
+            return Err(RouteWeaverError::TransportConnection);
+        }
+
+        let line = line.trim_end();
+        if line.is_empty() {
+            return Ok(lines);
+        }
+
+        lines.push(line.to_string());
+    }
+}
+
+async fn read_request(
+    stream: &mut HttpStream,
+    path_prefix: &str,
+) -> Result<(String, Direction), RouteWeaverError> {
+    let head = read_head(stream).await?;
+    let request_line = head.first().ok_or(RouteWeaverError::TransportConnection)?;
+
+    let mut parts = request_line.split_whitespace();
+    let (Some(method), Some(path)) = (parts.next(), parts.next()) else {
+        return Err(RouteWeaverError::TransportConnection);
+    };
+
+    let (link_id, direction) = path
+        .strip_prefix(path_prefix)
+        .and_then(|path| path.strip_prefix('/'))
+        .and_then(|path| path.split_once('/'))
+        .ok_or(RouteWeaverError::TransportConnection)?;
+
+    let direction = match (method, direction) {
+        ("POST", "up") => Direction::Up,
+        ("GET", "down") => Direction::Down,
+        _ => return Err(RouteWeaverError::TransportConnection),
+    };
+
+    if link_id.is_empty() || !link_id.chars().all(|c| c.is_ascii_hexdigit()) {
+        return Err(RouteWeaverError::TransportConnection);
+    }
+
+    Ok((link_id.to_string(), direction))
+}
+
+#[derive(Debug)]
+pub struct HttpPacketWriter {
+    writer: FramedWrite<HttpStream, ChunkedPacketEncoderDecoder>,
+    // Whether the last chunk has been queued up already
+    finished: bool,
+}
+
+impl HttpPacketWriter {
+    fn new(writer: HttpStream) -> Self {
+        Self {
+            writer: FramedWrite::new(writer, ChunkedPacketEncoderDecoder),
+            finished: false,
+        }
+    }
+}
+
+impl TransportWriter for HttpPacketWriter {}
+
+impl Sink<Packet> for HttpPacketWriter {
+    type Error = RouteWeaverError;
+
+    fn poll_ready(
+        mut self: Pin<&mut Self>,
+        cx: &mut std::task::Context<'_>,
+    ) -> std::task::Poll<Result<(), Self::Error>> {
+        pin!(&mut self.writer).poll_ready(cx)
+    }
+
+    fn start_send(mut self: Pin<&mut Self>, item: Packet) -> Result<(), Self::Error> {
+        pin!(&mut self.writer).start_send(item)
+    }
+
+    fn poll_flush(
+        mut self: Pin<&mut Self>,
+        cx: &mut std::task::Context<'_>,
+    ) -> std::task::Poll<Result<(), Self::Error>> {
+        pin!(&mut self.writer).poll_flush(cx)
+    }
+
+    fn poll_close(
+        mut self: Pin<&mut Self>,
+        cx: &mut std::task::Context<'_>,
+    ) -> std::task::Poll<Result<(), Self::Error>> {
+        // Flushed out behind whatever is still buffered before the stream gets shut down
+        if !self.finished {
+            self.writer.write_buffer_mut().put_slice(LAST_CHUNK);
+            self.finished = true;
+        }
+
+        pin!(&mut self.writer).poll_close(cx)
+    }
+}
+
+#[derive(Debug)]
+pub struct HttpPacketReader {
+    reader: FramedRead<HttpStream, ChunkedPacketEncoderDecoder>,
+}
+
+impl HttpPacketReader {
+    fn new(reader: HttpStream) -> Self {
+        Self {
+            reader: FramedRead::new(reader, ChunkedPacketEncoderDecoder),
+        }
+    }
+}
+
+impl TransportReader for HttpPacketReader {}
+
+impl Stream for HttpPacketReader {
+    type Item = Result<Packet, RouteWeaverError>;
+
+    fn poll_next(
+        mut self: Pin<&mut Self>,
+        cx: &mut std::task::Context<'_>,
+    ) -> std::task::Poll<Option<Self::Item>> {
+        pin!(&mut self.reader).poll_next(cx)
+    }
+}
+
+/// One packet per chunk of a chunked transfer encoded body
+#[derive(Default, Debug)]
+pub struct ChunkedPacketEncoderDecoder;
+
+impl Decoder for ChunkedPacketEncoderDecoder {
+    type Item = Packet;
+    type Error = RouteWeaverError;
+
+    fn decode(&mut self, src: &mut BytesMut) -> Result<Option<Self::Item>, Self::Error> {
+        loop {
+            let Some(header_length) = src.windows(2).position(|window| window == b"\r\n") else {
+                if src.len() > MAX_HEAD_SIZE {
+                    return Err(RouteWeaverError::TransportConnection);
+                }
+
+                return Ok(None);
+            };
+
+            // Chunk extensions are allowed after the size but mean nothing to us
+            let size = std::str::from_utf8(&src[..header_length])
+                .ok()
+                .and_then(|header| header.split(';').next())
+                .and_then(|size| usize::from_str_radix(size.trim(), 16).ok())
+                .ok_or(RouteWeaverError::TransportConnection)?;
+
+            if size == 0 {
+                // The peer ended its body, so this link is finished
+                return Err(RouteWeaverError::TransportConnection);
+            }
+
+            if size > MAX_FRAME_SIZE {
+                return Err(RouteWeaverError::TransportConnection);
+            }
+
+            let chunk_end = header_length + 2 + size;
+            if src.len() < chunk_end + 2 {
+                src.reserve(chunk_end + 2 - src.len());
+                return Ok(None);
+            }
+
+            if &src[chunk_end..chunk_end + 2] != b"\r\n" {
+                return Err(RouteWeaverError::TransportConnection);
+            }
+
+            let packet = PacketEncoderDecoder::decode_frame(&src[header_length + 2..chunk_end])?;
+            src.advance(chunk_end + 2);
+
+            // A damaged frame only costs us its own chunk
+            if packet.is_some() {
+                return Ok(packet);
+            }
+        }
+    }
+}
+
+impl Encoder<Packet> for ChunkedPacketEncoderDecoder {
+    type Error = RouteWeaverError;
+
+    fn encode(&mut self, item: Packet, dst: &mut BytesMut) -> Result<(), Self::Error> {
+        let packet = PacketEncoderDecoder::encode_frame(item)?;
+
+        dst.put_slice(format!("{:x}\r\n", packet.len()).as_bytes());
+        dst.put_slice(&packet);
+        dst.put_slice(b"\r\n");
+
+        Ok(())
+    }
+}
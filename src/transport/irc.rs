@@ -0,0 +1,558 @@
+use super::{
+    Connection, PacketEncoderDecoder, Transport, TransportReader, TransportWriter, BASE64_NOPAD,
+    MAX_FRAME_SIZE,
+};
+use crate::{
+    config::TransportConfig,
+    error::RouteWeaverError,
+    proto::{Address, Packet, Protocol, MAX_MESSAGE_SEGMENT_SIZE},
+};
+use dashmap::DashMap;
+use data_encoding::HEXLOWER;
+use deadqueue::unlimited::Queue;
+use futures_util::{Sink, Stream};
+use std::{
+    collections::HashMap,
+    pin::{pin, Pin},
+    sync::Arc,
+    task::Poll,
+    time::Duration,
+};
+use tokio::{
+    io::{AsyncBufReadExt, AsyncWriteExt, BufReader, Lines},
+    net::{
+        tcp::{OwnedReadHalf, OwnedWriteHalf},
+        TcpStream,
+    },
+    sync::mpsc::{channel, error::TrySendError, Receiver, Sender},
+    time::{sleep, timeout},
+};
+use tokio_util::{
+    sync::{CancellationToken, PollSender},
+    task::TaskTracker,
+};
+
+const DEFAULT_SERVER: &str = "127.0.0.1:6667";
+const DEFAULT_CHANNEL: &str = "#routeweaver";
+// Anything bigger just means hundreds of lines per segment
+const DEFAULT_SEGMENT_SIZE: usize = 2048;
+const REGISTRATION_TIMEOUT: Duration = Duration::from_secs(30);
+// How long we wait for the server to hang up on us after we QUIT
+const QUIT_TIMEOUT: Duration = Duration::from_secs(5);
+
+// RFC 1459 caps a line at 512 bytes including the trailing CRLF
+const MAX_LINE_LENGTH: usize = 512;
+// Room for the ":nick!user@host " prefix the server puts in front of our lines when relaying them
+const RELAY_PREFIX_ALLOWANCE: usize = 128;
+// Sent as the first line of a link so the other side knows to accept it
+const HELLO: &str = "HELLO";
+// Every fragment starts with one of these to mark whether it finishes its packet
+const MORE_FRAGMENTS: char = '+';
+const LAST_FRAGMENT: char = '.';
+
+type LinkTracker = Arc<DashMap<String, Sender<Result<Packet, RouteWeaverError>>>>;
+
+/// Exchanges packets with other nodes sitting in the same IRC channel
+///
+/// Every line we send is addressed to one nick inside the channel, so each nick we talk to becomes its
+/// own link. Packets are base64 encoded and split over as many PRIVMSG lines as they need.
+pub struct IrcTransport {
+    channel: Arc<IrcChannel>,
+    accepted: Arc<Queue<(Connection<Self>, Option<Address>)>>,
+    // Reading and writing the server connection, which closes once both are done
+    stop: CancellationToken,
+    tasks: TaskTracker,
+}
+
+impl Transport for IrcTransport {
+    const PROTOCOL: Protocol = Protocol::Irc;
+
+    type Reader = IrcPacketReader;
+    type Writer = IrcPacketWriter;
+
+    async fn new(config: Option<&TransportConfig>) -> Result<Self, RouteWeaverError>
+    where
+        Self: Sized,
+    {
+        let get_str = |key| {
+            config
+                .and_then(|config| config.get(key))
+                .and_then(|value| value.as_str())
+        };
+
+        let server = get_str("server").unwrap_or(DEFAULT_SERVER).to_string();
+        let channel_name = get_str("channel").unwrap_or(DEFAULT_CHANNEL).to_string();
+        let nick = get_str("nick").map_or_else(
+            || format!("rw{}", HEXLOWER.encode(&rand::random::<[u8; 4]>())),
+            String::from,
+        );
+        let invalid = |key| {
+            RouteWeaverError::TransportConfig(format!("{} has an invalid {}", Self::PROTOCOL, key))
+        };
+
+        // Anything bigger would not fit in a single frame
+        let segment_size = match config.and_then(|config| config.get("segment_size")) {
+            Some(value) => value
+                .as_integer()
+                .and_then(|size| usize::try_from(size).ok())
+                .filter(|size| (1..=MAX_MESSAGE_SEGMENT_SIZE).contains(size))
+                .ok_or_else(|| invalid("segment_size"))?,
+            None => DEFAULT_SEGMENT_SIZE,
+        };
+        // Most networks will disconnect us if we flood them, so allow spacing our lines out
+        let line_interval = match config.and_then(|config| config.get("line_interval_ms")) {
+            Some(value) => value
+                .as_integer()
+                .and_then(|interval| u64::try_from(interval).ok())
+                .map(Duration::from_millis)
+                .ok_or_else(|| invalid("line_interval_ms"))?,
+            None => Duration::ZERO,
+        };
+
+        let (reader, mut writer) = TcpStream::connect(&server).await?.into_split();
+        let mut lines = BufReader::new(reader).lines();
+
+        writer
+            .write_all(format!("NICK {nick}\r\nUSER {nick} 0 * :routeweaver\r\n").as_bytes())
+            .await?;
+
+        timeout(
+            REGISTRATION_TIMEOUT,
+            wait_for_registration(&mut lines, &mut writer),
+        )
+        .await
+        .map_err(|_| RouteWeaverError::TransportConnection)??;
+
+        writer
+            .write_all(format!("JOIN {channel_name}\r\n").as_bytes())
+            .await?;
+
+        log::info!("Joined {} on {} as {}", channel_name, server, nick);
+
+        let (outgoing, outgoing_receiver) = channel(1024);
+        let channel = Arc::new(IrcChannel {
+            nick,
+            name: channel_name,
+            segment_size,
+            outgoing,
+            links: LinkTracker::default(),
+        });
+        let accepted = Arc::new(Queue::new());
+        let stop = CancellationToken::new();
+        let tasks = TaskTracker::new();
+
+        tasks.spawn(write_irc_lines(
+            writer,
+            outgoing_receiver,
+            line_interval,
+            stop.clone(),
+        ));
+        tasks.spawn(read_irc_lines(
+            lines,
+            channel.clone(),
+            accepted.clone(),
+            stop.clone(),
+        ));
+
+        Ok(Self {
+            channel,
+            accepted,
+            stop,
+            tasks,
+        })
+    }
+
+    async fn connect(
+        self: Arc<Self>,
+        address: Option<&Address>,
+    ) -> Result<Connection<Self>, RouteWeaverError> {
+        let Some(Address::Nick(remote_nick)) = address else {
+            return Err(RouteWeaverError::PeerAddress);
+        };
+
+        let connection = self.channel.create_link(remote_nick);
+
+        self.channel
+            .outgoing
+            .send(vec![format!(
+                "PRIVMSG {} :{} {}",
+                self.channel.name, remote_nick, HELLO
+            )])
+            .await
+            .map_err(|_| RouteWeaverError::TransportConnection)?;
+
+        Ok(connection)
+    }
+
+    async fn accept(
+        self: Arc<Self>,
+    ) -> Result<(Connection<Self>, Option<Address>), RouteWeaverError> {
+        Ok(self.accepted.pop().await)
+    }
+
+    fn recommended_message_segment_size(&self) -> Option<usize> {
+        Some(self.channel.segment_size)
+    }
+
+    async fn shutdown(&self) {
+        self.stop.cancel();
+        self.tasks.close();
+        self.tasks.wait().await;
+    }
+}
+
+async fn wait_for_registration(
+    lines: &mut Lines<BufReader<OwnedReadHalf>>,
+    writer: &mut OwnedWriteHalf,
+) -> Result<(), RouteWeaverError> {
+    while let Some(line) = lines.next_line().await? {
+        let message = IrcMessage::parse(&line);
+
+        match message.command {
+            "PING" => {
+                writer
+                    .write_all(format!("PONG :{}\r\n", message.trailing()).as_bytes())
+                    .await?;
+            }
+            // RPL_WELCOME
+            "001" => return Ok(()),
+            // ERR_NICKNAMEINUSE and friends
+            "432" | "433" | "436" => {
+                log::error!("IRC server refused our nick: {}", line);
+                return Err(RouteWeaverError::TransportConnection);
+            }
+            _ => {}
+        }
+    }
+
+    Err(RouteWeaverError::TransportConnection)
+}
+
+async fn write_irc_lines(
+    mut writer: OwnedWriteHalf,
+    mut outgoing: Receiver<Vec<String>>,
+    line_interval: Duration,
+    stop: CancellationToken,
+) {
+    loop {
+        let lines = tokio::select! {
+            // Whatever is already queued still goes out before we leave
+            biased;
+            lines = outgoing.recv() => match lines {
+                Some(lines) => lines,
+                None => break,
+            },
+            _ = stop.cancelled() => break,
+        };
+
+        for line in lines {
+            if let Err(e) = writer.write_all(format!("{line}\r\n").as_bytes()).await {
+                log::error!("Lost connection to IRC server: {}", e);
+                return;
+            }
+
+            if !line_interval.is_zero() {
+                sleep(line_interval).await;
+            }
+        }
+    }
+
+    // Leaving properly frees our nick right away, instead of once the server notices we are gone
+    let _ = writer.write_all(b"QUIT\r\n").await;
+    let _ = writer.shutdown().await;
+}
+
+async fn read_irc_lines(
+    mut lines: Lines<BufReader<OwnedReadHalf>>,
+    channel: Arc<IrcChannel>,
+    accepted: Arc<Queue<(Connection<IrcTransport>, Option<Address>)>>,
+    stop: CancellationToken,
+) {
+    // Base64 text of packets still waiting on their last fragment
+    let mut partial_packets: HashMap<String, String> = HashMap::new();
+    let max_encoded_length = BASE64_NOPAD.encode_len(MAX_FRAME_SIZE);
+
+    loop {
+        let line = tokio::select! {
+            line = lines.next_line() => line,
+            _ = stop.cancelled() => {
+                // The server hangs up once it has seen our QUIT
+                let _ = timeout(QUIT_TIMEOUT, async {
+                    while let Ok(Some(_)) = lines.next_line().await {}
+                })
+                .await;
+                break;
+            }
+        };
+
+        let line = match line {
+            Ok(Some(line)) => line,
+            Ok(None) => {
+                log::error!("IRC server closed the connection");
+                break;
+            }
+            Err(e) => {
+                log::error!("Lost connection to IRC server: {}", e);
+                break;
+            }
+        };
+
+        let message = IrcMessage::parse(&line);
+
+        match message.command {
+            "PING" => {
+                let _ = channel
+                    .outgoing
+                    .send(vec![format!("PONG :{}", message.trailing())])
+                    .await;
+            }
+            "PRIVMSG" if message.params.first() == Some(&channel.name.as_str()) => {
+                let Some(sender) = message.sender_nick() else {
+                    continue;
+                };
+
+                let Some((target, content)) = message.trailing().split_once(' ') else {
+                    continue;
+                };
+
+                if !target.eq_ignore_ascii_case(&channel.nick) {
+                    continue;
+                }
+
+                let sender_key = sender.to_ascii_lowercase();
+
+                if content == HELLO {
+                    // A repeated hello means the other side started over, so the old link goes away
+                    partial_packets.remove(&sender_key);
+                    let connection = channel.create_link(sender);
+                    accepted.push((connection, Some(Address::Nick(sender.to_string()))));
+                    continue;
+                }
+
+                let Some(link) = channel.links.get(&sender_key).map(|link| link.clone()) else {
+                    continue;
+                };
+
+                let mut fragment = content.chars();
+                let marker = fragment.next();
+                let fragment = fragment.as_str();
+
+                let buffer = partial_packets.entry(sender_key.clone()).or_default();
+                buffer.push_str(fragment);
+
+                if buffer.len() > max_encoded_length {
+                    log::warn!("Dropping oversized packet from IRC nick {}", sender);
+                    partial_packets.remove(&sender_key);
+                    continue;
+                }
+
+                match marker {
+                    Some(MORE_FRAGMENTS) => continue,
+                    Some(LAST_FRAGMENT) => {}
+                    _ => {
+                        log::warn!("Malformed fragment from IRC nick {}", sender);
+                        partial_packets.remove(&sender_key);
+                        continue;
+                    }
+                }
+
+                let encoded = partial_packets.remove(&sender_key).unwrap_or_default();
+                let packet = BASE64_NOPAD
+                    .decode(encoded.as_bytes())
+                    .map_err(|_| RouteWeaverError::PacketEncoding)
+                    .and_then(|frame| PacketEncoderDecoder::decode_frame(&frame))
+                    .transpose();
+
+                // Damaged frames are simply dropped
+                let Some(packet) = packet else {
+                    continue;
+                };
+
+                // Losing a packet beats holding up every other link, and the pings keeping us connected
+                match link.try_send(packet) {
+                    Ok(()) => {}
+                    Err(TrySendError::Full(_)) => {
+                        log::warn!(
+                            "Dropping packet from IRC nick {} as its link is backed up",
+                            sender
+                        );
+                    }
+                    Err(TrySendError::Closed(_)) => {
+                        channel.links.remove(&sender_key);
+                    }
+                }
+            }
+            _ => {}
+        }
+    }
+
+    // Ending every link lets the sessions on top of them notice
+    channel.links.clear();
+}
+
+/// Our presence in the channel that every link is multiplexed over
+struct IrcChannel {
+    nick: String,
+    name: String,
+    segment_size: usize,
+    outgoing: Sender<Vec<String>>,
+    links: LinkTracker,
+}
+
+impl IrcChannel {
+    fn create_link(&self, remote_nick: &str) -> Connection<IrcTransport> {
+        let (sender, receiver) = channel(1024);
+        self.links.insert(remote_nick.to_ascii_lowercase(), sender);
+
+        let line_prefix = format!("PRIVMSG {} :{} ", self.name, remote_nick);
+        let fragment_size = MAX_LINE_LENGTH
+            .saturating_sub(line_prefix.len() + RELAY_PREFIX_ALLOWANCE + 2 + 1)
+            .max(1);
+
+        (
+            IrcPacketReader { receiver },
+            IrcPacketWriter {
+                sender: PollSender::new(self.outgoing.clone()),
+                line_prefix,
+                fragment_size,
+            },
+        )
+    }
+}
+
+/// Just enough of an IRC line to route it
+struct IrcMessage<'a> {
+    prefix: Option<&'a str>,
+    command: &'a str,
+    params: Vec<&'a str>,
+    trailing: Option<&'a str>,
+}
+
+impl<'a> IrcMessage<'a> {
+    fn parse(line: &'a str) -> Self {
+        let mut rest = line.trim_end_matches(['\r', '\n']);
+
+        let prefix = rest.strip_prefix(':').map(|stripped| {
+            let (prefix, remaining) = stripped.split_once(' ').unwrap_or((stripped, ""));
+            rest = remaining;
+            prefix
+        });
+
+        let (rest, trailing) = match rest.split_once(" :") {
+            Some((rest, trailing)) => (rest, Some(trailing)),
+            None => (rest, None),
+        };
+
+        let mut parts = rest.split_whitespace();
+        let command = parts.next().unwrap_or_default();
+
+        Self {
+            prefix,
+            command,
+            params: parts.collect(),
+            trailing,
+        }
+    }
+
+    fn trailing(&self) -> &'a str {
+        self.trailing
+            .or_else(|| self.params.last().copied())
+            .unwrap_or_default()
+    }
+
+    fn sender_nick(&self) -> Option<&'a str> {
+        self.prefix
+            .map(|prefix| prefix.split(['!', '@']).next().unwrap_or(prefix))
+    }
+}
+
+#[derive(Debug)]
+pub struct IrcPacketWriter {
+    sender: PollSender<Vec<String>>,
+    line_prefix: String,
+    fragment_size: usize,
+}
+
+impl TransportWriter for IrcPacketWriter {}
+
+impl Sink<Packet> for IrcPacketWriter {
+    type Error = RouteWeaverError;
+
+    fn poll_ready(
+        mut self: Pin<&mut Self>,
+        cx: &mut std::task::Context<'_>,
+    ) -> Poll<Result<(), Self::Error>> {
+        pin!(&mut self.sender)
+            .poll_ready(cx)
+            .map_err(|_| RouteWeaverError::TransportConnection)
+    }
+
+    fn start_send(mut self: Pin<&mut Self>, item: Packet) -> Result<(), Self::Error> {
+        let packet = PacketEncoderDecoder::encode_frame(item)?;
+        let encoded = BASE64_NOPAD.encode(&packet);
+
+        // Base64 is pure ASCII so splitting on bytes never cuts a character in half
+        let fragments = encoded
+            .as_bytes()
+            .chunks(self.fragment_size)
+            .collect::<Vec<_>>();
+        let lines = fragments
+            .iter()
+            .enumerate()
+            .map(|(index, fragment)| {
+                let marker = if index + 1 == fragments.len() {
+                    LAST_FRAGMENT
+                } else {
+                    MORE_FRAGMENTS
+                };
+
+                format!(
+                    "{}{}{}",
+                    self.line_prefix,
+                    marker,
+                    std::str::from_utf8(fragment).unwrap()
+                )
+            })
+            .collect();
+
+        pin!(&mut self.sender)
+            .start_send(lines)
+            .map_err(|_| RouteWeaverError::TransportConnection)
+    }
+
+    fn poll_flush(
+        mut self: Pin<&mut Self>,
+        cx: &mut std::task::Context<'_>,
+    ) -> Poll<Result<(), Self::Error>> {
+        pin!(&mut self.sender)
+            .poll_flush(cx)
+            .map_err(|_| RouteWeaverError::TransportConnection)
+    }
+
+    fn poll_close(
+        mut self: Pin<&mut Self>,
+        cx: &mut std::task::Context<'_>,
+    ) -> Poll<Result<(), Self::Error>> {
+        pin!(&mut self.sender)
+            .poll_close(cx)
+            .map_err(|_| RouteWeaverError::TransportConnection)
+    }
+}
+
+#[derive(Debug)]
+pub struct IrcPacketReader {
+    receiver: Receiver<Result<Packet, RouteWeaverError>>,
+}
+
+impl TransportReader for IrcPacketReader {}
+
+impl Stream for IrcPacketReader {
+    type Item = Result<Packet, RouteWeaverError>;
+
+    fn poll_next(
+        mut self: Pin<&mut Self>,
+        cx: &mut std::task::Context<'_>,
+    ) -> Poll<Option<Self::Item>> {
+        self.receiver.poll_recv(cx)
+    }
+}
@@ -1,3 +1,7 @@
+#[cfg(http_transport)]
+pub mod http;
+#[cfg(irc_transport)]
+pub mod irc;
 #[cfg(tcp_transport)]
 pub mod tcp;
 #[cfg(unix_transport)]
@@ -6,17 +10,18 @@ pub mod unix;
 use crate::{
     config::TransportConfig,
     error::RouteWeaverError,
-    proto::{Address, Packet, Protocol, BINCODE_PACKET_CONFIG},
+    proto::{
+        Address, Packet, Protocol, BINCODE_PACKET_CONFIG, MAX_SERIALIZED_PACKET_SIZE,
+        PROTOCOL_EDITION,
+    },
 };
-use bincode::{
-    error::DecodeError,
-    serde::{decode_from_std_read, encode_into_std_write},
-};
-use bytes::{buf::Writer, BytesMut};
+use bincode::serde::{decode_from_slice, encode_to_vec};
+use blake2::{Blake2s256, Digest};
+use bytes::BytesMut;
 use data_encoding::BASE64_NOPAD;
-use futures_util::{Sink, SinkExt, Stream};
+use futures_util::{Sink, Stream};
 use std::pin::{pin, Pin};
-use std::{fmt::Debug, sync::Arc};
+use std::{fmt::Debug, future::Future, sync::Arc};
 use tokio::io::{AsyncRead, AsyncWrite};
 use tokio_util::{
     bytes::{Buf, BufMut},
@@ -30,28 +35,50 @@ pub trait TransportReader:
 {
 }
 
+/// Whether support for a protocol was compiled into this build
+pub fn is_available(protocol: Protocol) -> bool {
+    match protocol {
+        Protocol::Tcp => cfg!(tcp_transport),
+        Protocol::Unix => cfg!(unix_transport),
+        Protocol::Http => cfg!(http_transport),
+        Protocol::Irc => cfg!(irc_transport),
+        Protocol::Bluetooth => false,
+    }
+}
+
+pub type Connection<T> = (<T as Transport>::Reader, <T as Transport>::Writer);
+
 pub trait Transport: Send + Sync + 'static {
     const PROTOCOL: Protocol;
 
     type Reader: TransportReader;
     type Writer: TransportWriter;
 
-    async fn new(config: Option<&TransportConfig>) -> Result<Self, RouteWeaverError>
+    fn new(
+        config: Option<&TransportConfig>,
+    ) -> impl Future<Output = Result<Self, RouteWeaverError>> + Send
     where
         Self: Sized;
 
-    async fn connect(
+    fn connect(
         self: Arc<Self>,
         address: Option<&Address>,
-    ) -> Result<(Self::Reader, Self::Writer), RouteWeaverError>;
+    ) -> impl Future<Output = Result<Connection<Self>, RouteWeaverError>> + Send;
 
-    async fn accept(
+    fn accept(
         self: Arc<Self>,
-    ) -> Result<((Self::Reader, Self::Writer), Option<Address>), RouteWeaverError>;
+    ) -> impl Future<Output = Result<(Connection<Self>, Option<Address>), RouteWeaverError>> + Send;
 
     fn recommended_message_segment_size(&self) -> Option<usize> {
         None
     }
+
+    /// Stop whatever the transport runs in the background and clean up what it left outside the process
+    ///
+    /// Called once it stops accepting, and only returns when it is safe to start the transport again
+    fn shutdown(&self) -> impl Future<Output = ()> + Send {
+        async {}
+    }
 }
 
 #[derive(Debug)]
@@ -124,30 +151,117 @@ impl<T: AsyncRead + Debug + Send + Sync + Unpin> Stream for PlainBincodePacketRe
     }
 }
 
+// Marks the start of every frame so a reader can find its footing again after garbage
+const FRAME_MAGIC: [u8; 2] = *b"RW";
+// Magic, edition, payload length and checksum
+pub const FRAME_HEADER_SIZE: usize = FRAME_MAGIC.len() + 1 + 4 + 4;
+pub const MAX_FRAME_SIZE: usize = FRAME_HEADER_SIZE + MAX_SERIALIZED_PACKET_SIZE;
+
+pub fn frame_checksum(edition: u8, payload: &[u8]) -> [u8; 4] {
+    let mut hasher = Blake2s256::new();
+    hasher.update([edition]);
+    hasher.update((payload.len() as u32).to_be_bytes());
+    hasher.update(payload);
+
+    hasher.finalize()[..4].try_into().unwrap()
+}
+
+/// Frames each packet as magic, edition, big endian length, checksum and then the bincode payload
 #[derive(Default, Debug)]
 pub struct PacketEncoderDecoder;
 
+impl PacketEncoderDecoder {
+    /// Frame a lone packet for transports that already keep their own message boundaries
+    pub fn encode_frame(packet: Packet) -> Result<BytesMut, RouteWeaverError> {
+        let mut frame = BytesMut::new();
+        Self.encode(packet, &mut frame)?;
+
+        Ok(frame)
+    }
+
+    /// Decode a buffer that should hold exactly one frame, giving nothing back if it was damaged
+    pub fn decode_frame(frame: &[u8]) -> Result<Option<Packet>, RouteWeaverError> {
+        Self.decode(&mut BytesMut::from(frame))
+    }
+}
+
 impl Decoder for PacketEncoderDecoder {
     type Item = Packet;
     type Error = RouteWeaverError;
 
     fn decode(&mut self, src: &mut BytesMut) -> Result<Option<Self::Item>, Self::Error> {
-        if src.is_empty() {
-            return Ok(None);
-        }
+        loop {
+            match src
+                .windows(FRAME_MAGIC.len())
+                .position(|window| window == FRAME_MAGIC)
+            {
+                Some(0) => {}
+                Some(garbage) => {
+                    log::warn!(
+                        "Skipping {} bytes of garbage before the next frame",
+                        garbage
+                    );
+                    src.advance(garbage);
+                }
+                None => {
+                    // Hold on to a trailing byte in case it is the start of the next magic
+                    let keep = usize::from(src.last() == Some(&FRAME_MAGIC[0]));
+                    let garbage = src.len() - keep;
 
-        // On purpose our packets don't have any magic bytes
+                    if garbage != 0 {
+                        log::warn!(
+                            "Skipping {} bytes of garbage before the next frame",
+                            garbage
+                        );
+                        src.advance(garbage);
+                    }
 
-        match decode_from_std_read(&mut src.reader(), BINCODE_PACKET_CONFIG) {
-            Ok(packet) => Ok(Some(packet)),
-            Err(e) => match e {
-                DecodeError::UnexpectedEnd { additional } => {
-                    log::trace!("Not enough bytes to decode packet: {}", additional);
-                    // We haven't gotten enough bytes to deserialize
-                    Ok(None)
+                    return Ok(None);
                 }
-                _ => Err(RouteWeaverError::PacketDecoding(e)),
-            },
+            }
+
+            if src.len() < FRAME_HEADER_SIZE {
+                src.reserve(FRAME_HEADER_SIZE - src.len());
+                return Ok(None);
+            }
+
+            let edition = src[FRAME_MAGIC.len()];
+            let length =
+                u32::from_be_bytes(src[FRAME_MAGIC.len() + 1..][..4].try_into().unwrap()) as usize;
+            let checksum: [u8; 4] = src[FRAME_MAGIC.len() + 5..][..4].try_into().unwrap();
+
+            if length > MAX_SERIALIZED_PACKET_SIZE {
+                log::warn!("Frame claims an impossible length of {} bytes", length);
+                // Whatever this was, it was not the start of a frame
+                src.advance(FRAME_MAGIC.len());
+                continue;
+            }
+
+            if src.len() < FRAME_HEADER_SIZE + length {
+                src.reserve(FRAME_HEADER_SIZE + length - src.len());
+                return Ok(None);
+            }
+
+            let payload = &src[FRAME_HEADER_SIZE..][..length];
+
+            if frame_checksum(edition, payload) != checksum {
+                log::warn!("Frame failed its checksum, resynchronizing");
+                src.advance(FRAME_MAGIC.len());
+                continue;
+            }
+
+            // The frame is intact, so the peer really does speak another edition
+            if edition != PROTOCOL_EDITION {
+                return Err(RouteWeaverError::IncompatibleEdition(edition));
+            }
+
+            let packet = decode_from_slice(payload, BINCODE_PACKET_CONFIG);
+            src.advance(FRAME_HEADER_SIZE + length);
+
+            match packet {
+                Ok((packet, _)) => return Ok(Some(packet)),
+                Err(e) => log::warn!("Skipping frame holding an undecodable packet: {}", e),
+            }
         }
     }
 }
@@ -156,9 +270,15 @@ impl Encoder<Packet> for PacketEncoderDecoder {
     type Error = RouteWeaverError;
 
     fn encode(&mut self, item: Packet, dst: &mut BytesMut) -> Result<(), Self::Error> {
-        if encode_into_std_write(item, &mut dst.writer(), BINCODE_PACKET_CONFIG).is_err() {
-            return Err(RouteWeaverError::PacketEncoding);
-        }
+        let payload = encode_to_vec(item, BINCODE_PACKET_CONFIG)
+            .map_err(|_| RouteWeaverError::PacketEncoding)?;
+
+        dst.reserve(FRAME_HEADER_SIZE + payload.len());
+        dst.put_slice(&FRAME_MAGIC);
+        dst.put_u8(PROTOCOL_EDITION);
+        dst.put_u32(payload.len() as u32);
+        dst.put_slice(&frame_checksum(PROTOCOL_EDITION, &payload));
+        dst.put_slice(&payload);
 
         Ok(())
     }
@@ -1,12 +1,12 @@
-use super::{PlainBincodePacketReader, PlainBincodePacketWriter, Transport};
+use super::{Connection, PlainBincodePacketReader, PlainBincodePacketWriter, Transport};
 use crate::{
     config::TransportConfig,
     error::RouteWeaverError,
     proto::{Address, Protocol},
 };
-use socket2::Socket;
+use socket2::{Domain, Socket};
 use std::{
-    net::{IpAddr, SocketAddr},
+    net::{IpAddr, Ipv6Addr, SocketAddr},
     sync::Arc,
 };
 use tokio::net::{
@@ -14,7 +14,12 @@ use tokio::net::{
     TcpListener, TcpStream,
 };
 
+const DEFAULT_BIND_ADDRESS: IpAddr = IpAddr::V6(Ipv6Addr::UNSPECIFIED);
+const DEFAULT_PORT: u16 = 3434;
+const DEFAULT_BACKLOG: i32 = 128;
+
 pub struct TcpTransport {
+    port: u16,
     socket: TcpListener,
 }
 
@@ -24,46 +29,79 @@ impl Transport for TcpTransport {
     type Reader = PlainBincodePacketReader<OwnedReadHalf>;
     type Writer = PlainBincodePacketWriter<OwnedWriteHalf>;
 
-    async fn new(_config: Option<&TransportConfig>) -> Result<Self, RouteWeaverError>
+    async fn new(config: Option<&TransportConfig>) -> Result<Self, RouteWeaverError>
     where
         Self: Sized,
     {
+        let invalid = |key| {
+            RouteWeaverError::TransportConfig(format!("{} has an invalid {}", Self::PROTOCOL, key))
+        };
+
+        let bind_address = match config.and_then(|config| config.get("bind")) {
+            Some(value) => value
+                .as_str()
+                .and_then(|address| address.parse().ok())
+                .ok_or_else(|| invalid("bind"))?,
+            None => DEFAULT_BIND_ADDRESS,
+        };
+
+        let port = match config.and_then(|config| config.get("port")) {
+            Some(value) => value
+                .as_integer()
+                .and_then(|port| u16::try_from(port).ok())
+                .ok_or_else(|| invalid("port"))?,
+            None => DEFAULT_PORT,
+        };
+
+        let backlog = match config.and_then(|config| config.get("backlog")) {
+            Some(value) => value
+                .as_integer()
+                .and_then(|backlog| i32::try_from(backlog).ok())
+                .ok_or_else(|| invalid("backlog"))?,
+            None => DEFAULT_BACKLOG,
+        };
+
+        // Dual stack unless told otherwise, so a single socket serves both families
+        let ipv6_only = match config.and_then(|config| config.get("ipv6_only")) {
+            Some(value) => value.as_bool().ok_or_else(|| invalid("ipv6_only"))?,
+            None => false,
+        };
+
         let socket = Socket::new(
-            socket2::Domain::IPV6,
+            Domain::for_address(SocketAddr::new(bind_address, port)),
             socket2::Type::STREAM,
             Some(socket2::Protocol::TCP),
         )?;
 
-        socket.set_only_v6(false).unwrap();
-        socket.set_nonblocking(true).unwrap();
-        socket.set_reuse_address(true).unwrap();
+        if bind_address.is_ipv6() {
+            socket.set_only_v6(ipv6_only)?;
+        }
+        socket.set_nonblocking(true)?;
+        socket.set_reuse_address(true)?;
 
-        socket
-            .bind(&SocketAddr::new("::".parse().unwrap(), 3434).into())
-            .unwrap();
+        socket.bind(&SocketAddr::new(bind_address, port).into())?;
+        socket.listen(backlog)?;
 
-        socket.listen(4).unwrap();
+        let socket = TcpListener::from_std(std::net::TcpListener::from(socket))?;
+        log::info!("Listening for TCP connections on {}", socket.local_addr()?);
 
         Ok(Self {
-            socket: TcpListener::from_std(std::net::TcpListener::from(socket)).unwrap(),
+            // Whatever we actually got if we asked for an ephemeral port
+            port: socket.local_addr()?.port(),
+            socket,
         })
     }
 
     async fn connect(
         self: Arc<Self>,
         address: Option<&Address>,
-    ) -> Result<(Self::Reader, Self::Writer), RouteWeaverError> {
-        let ip_addr = address
-            .and_then(|addr| {
-                if let Address::Ip(ip) = addr {
-                    return Some(*ip);
-                }
-
-                None
-            })
-            .expect("Need IP");
-
-        let addr = SocketAddr::new(ip_addr, 3434);
+    ) -> Result<Connection<Self>, RouteWeaverError> {
+        // Peers without an explicit port are assumed to be configured like us
+        let addr = match address {
+            Some(Address::Ip(ip_addr)) => SocketAddr::new(*ip_addr, self.port),
+            Some(Address::Socket(socket_addr)) => *socket_addr,
+            _ => return Err(RouteWeaverError::PeerAddress),
+        };
 
         Ok(TcpStream::connect(addr).await.map(|stream| {
             let (read, write) = stream.into_split();
@@ -1,4 +1,4 @@
-use super::{PlainBincodePacketReader, PlainBincodePacketWriter, Transport};
+use super::{Connection, PlainBincodePacketReader, PlainBincodePacketWriter, Transport};
 use crate::{
     config::TransportConfig,
     error::RouteWeaverError,
@@ -25,12 +25,10 @@ impl Transport for UnixTransport {
     where
         Self: Sized,
     {
+        // The socket doesn't exist yet so there's nothing to canonicalize
         let path = config
-            .and_then(|config| {
-                config
-                    .get("socket_path")
-                    .and_then(|value| value.as_str().map(PathBuf::from)?.canonicalize().ok())
-            })
+            .and_then(|config| config.get("socket_path"))
+            .and_then(|value| value.as_str().map(PathBuf::from))
             .unwrap_or_else(|| temp_dir().join("routeweaver-unix-transport"));
 
         // If it fails I don't really care
@@ -44,9 +42,16 @@ impl Transport for UnixTransport {
 
     async fn connect(
         self: Arc<Self>,
-        _address: Option<&Address>,
-    ) -> Result<(Self::Reader, Self::Writer), RouteWeaverError> {
-        Ok(UnixStream::connect(&self.path).await.map(|stream| {
+        address: Option<&Address>,
+    ) -> Result<Connection<Self>, RouteWeaverError> {
+        // Without an address we can only reach whoever else is listening on our own path
+        let path = match address {
+            Some(Address::Path(path)) => path,
+            None => &self.path,
+            _ => return Err(RouteWeaverError::PeerAddress),
+        };
+
+        Ok(UnixStream::connect(path).await.map(|stream| {
             let (read, write) = stream.into_split();
 
             (
@@ -70,4 +75,10 @@ impl Transport for UnixTransport {
             )
         })?)
     }
+
+    async fn shutdown(&self) {
+        if let Err(e) = remove_file(&self.path) {
+            log::warn!("Failed to remove socket {}: {}", self.path.display(), e);
+        }
+    }
 }